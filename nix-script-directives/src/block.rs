@@ -0,0 +1,434 @@
+use anyhow::{Context, Result};
+use rnix::ast::{self, HasEntry, InterpolPart};
+use rnix::Root;
+use rowan::ast::AstNode;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+const OPENING_FENCE: &str = "/// nix-script";
+
+const CLOSING_FENCE: &str = "///";
+
+/// Keys whose values are lists of Nix expressions. When these come from TOML
+/// strings we need to keep compound expressions (like function applications)
+/// together, so we wrap them in parentheses.
+const EXPR_LISTS: &[&str] = &["buildInputs", "runtimeInputs"];
+
+/// Keys whose values are lists of paths. These are split like shell words, so
+/// we quote paths coming from TOML or Nix strings.
+const FILE_LISTS: &[&str] = &["runtimeFiles", "buildFiles", "allowRead", "allowWrite"];
+
+/// A fenced metadata block inside a comment, as an alternative to directive
+/// lines. With `#` as the comment prefix, it looks like this:
+///
+/// ```text
+/// # /// nix-script
+/// # build = "cp $SRC $OUT"
+/// # runtimeInputs = [ "jq" ]
+/// # ///
+/// ```
+///
+/// The contents are either TOML or (if they start with `{`) a Nix attribute
+/// set.
+#[derive(Debug, PartialEq)]
+pub struct Block {
+    /// Indices of the lines containing the opening and closing fences.
+    pub lines: RangeInclusive<usize>,
+    pub content: String,
+}
+
+impl Block {
+    pub fn find(source: &str) -> Result<Option<Self>> {
+        let mut found: Option<Self> = None;
+        let mut lines = source.lines().enumerate();
+
+        while let Some((start, line)) = lines.next() {
+            let prefix = match line.trim_end().strip_suffix(OPENING_FENCE) {
+                Some(prefix) if !prefix.trim().is_empty() => prefix.trim_end(),
+                _ => continue,
+            };
+
+            if found.is_some() {
+                anyhow::bail!(
+                    "found a second `{}` block on line {}",
+                    OPENING_FENCE,
+                    start + 1
+                )
+            }
+
+            let closing = format!("{prefix} {CLOSING_FENCE}");
+            let mut content = Vec::new();
+            let mut end = None;
+
+            for (index, line) in lines.by_ref() {
+                if line.trim_end() == closing {
+                    end = Some(index);
+                    break;
+                }
+
+                match line.strip_prefix(prefix) {
+                    Some(rest) => content.push(rest.strip_prefix(' ').unwrap_or(rest)),
+                    None => anyhow::bail!(
+                        "line {} is inside the `{}` block starting on line {} but does not start with `{}`",
+                        index + 1,
+                        OPENING_FENCE,
+                        start + 1,
+                        prefix,
+                    ),
+                }
+            }
+
+            let end = end.with_context(|| {
+                format!(
+                    "the `{}` block starting on line {} is never closed with `{}`",
+                    OPENING_FENCE,
+                    start + 1,
+                    closing,
+                )
+            })?;
+
+            found = Some(Block {
+                lines: start..=end,
+                content: content.join("\n"),
+            });
+        }
+
+        Ok(found)
+    }
+
+    /// Blank out the lines of this block in the source, so they are not
+    /// mistaken for directive lines.
+    pub fn remove_from(&self, source: &str) -> String {
+        source
+            .lines()
+            .enumerate()
+            .map(|(index, line)| {
+                if self.lines.contains(&index) {
+                    ""
+                } else {
+                    line
+                }
+            })
+            .collect::<Vec<&str>>()
+            .join("\n")
+    }
+
+    /// Convert the block into the same shape the directive line parser
+    /// produces: a list of raw values per key.
+    pub fn fields(&self) -> Result<HashMap<String, Vec<String>>> {
+        if self.content.trim_start().starts_with('{') {
+            Self::fields_from_nix(&self.content)
+        } else {
+            Self::fields_from_toml(&self.content)
+        }
+    }
+
+    fn fields_from_toml(content: &str) -> Result<HashMap<String, Vec<String>>> {
+        let table: toml::Table = content
+            .parse()
+            .context("could not parse `/// nix-script` block as TOML")?;
+
+        let mut out = HashMap::new();
+
+        for (key, value) in table {
+            let values = match value {
+                toml::Value::String(string) => vec![string],
+                toml::Value::Array(items) => items
+                    .into_iter()
+                    .map(|item| match item {
+                        toml::Value::String(string)
+                            if EXPR_LISTS.contains(&key.as_str())
+                                && string.contains(char::is_whitespace) =>
+                        {
+                            format!("({string})")
+                        }
+                        toml::Value::String(string) if FILE_LISTS.contains(&key.as_str()) => {
+                            shell_words::quote(&string).into_owned()
+                        }
+                        toml::Value::String(string) => string,
+                        other => toml_to_nix(&other),
+                    })
+                    .collect(),
+                other => vec![toml_to_nix(&other)],
+            };
+
+            out.insert(key, values);
+        }
+
+        Ok(out)
+    }
+
+    fn fields_from_nix(content: &str) -> Result<HashMap<String, Vec<String>>> {
+        let root: Root = Root::parse(content)
+            .ok()
+            .context("could not parse `/// nix-script` block as a Nix expression")?;
+        let attrset = root
+            .expr()
+            .and_then(|expr| ast::AttrSet::cast(expr.syntax().clone()))
+            .context("a `/// nix-script` block starting with `{` must be a Nix attribute set")?;
+
+        let mut out = HashMap::new();
+
+        for entry in attrset.attrpath_values() {
+            let mut attrs = entry
+                .attrpath()
+                .context("attribute without a name in `/// nix-script` block")?
+                .attrs();
+
+            let key = match (attrs.next(), attrs.next()) {
+                (Some(ast::Attr::Ident(ident)), None) => ident.to_string(),
+                (Some(ast::Attr::Str(string)), None) => nix_string(&string)?,
+                _ => anyhow::bail!(
+                    "keys in a `/// nix-script` block must be plain names, but got `{}`",
+                    entry.attrpath().map(|a| a.to_string()).unwrap_or_default()
+                ),
+            };
+
+            let value = entry
+                .value()
+                .with_context(|| format!("`{key}` in `/// nix-script` block has no value"))?;
+
+            let values = match value {
+                ast::Expr::Str(string) => vec![nix_string(&string)?],
+                ast::Expr::List(list) => list
+                    .items()
+                    .map(|item| match item {
+                        ast::Expr::Str(string) if FILE_LISTS.contains(&key.as_str()) => {
+                            Ok(shell_words::quote(&nix_string(&string)?).into_owned())
+                        }
+                        ast::Expr::Str(string) => nix_string(&string),
+                        other => Ok(other.to_string()),
+                    })
+                    .collect::<Result<Vec<String>>>()?,
+                other => vec![other.to_string()],
+            };
+
+            out.insert(key, values);
+        }
+
+        Ok(out)
+    }
+}
+
+/// Get the contents of a Nix string literal. We can't evaluate anything, so
+/// interpolation is not allowed.
+fn nix_string(string: &ast::Str) -> Result<String> {
+    string
+        .normalized_parts()
+        .into_iter()
+        .map(|part| match part {
+            InterpolPart::Literal(literal) => Ok(literal),
+            InterpolPart::Interpolation(_) => anyhow::bail!(
+                "strings in a `/// nix-script` block cannot contain interpolations, but `{}` does",
+                string
+            ),
+        })
+        .collect()
+}
+
+fn toml_to_nix(value: &toml::Value) -> String {
+    match value {
+        toml::Value::String(string) => nix_quote(string),
+        toml::Value::Integer(integer) => integer.to_string(),
+        toml::Value::Float(float) => float.to_string(),
+        toml::Value::Boolean(boolean) => boolean.to_string(),
+        toml::Value::Datetime(datetime) => format!("\"{datetime}\""),
+        toml::Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(toml_to_nix).collect();
+            format!("[ {} ]", items.join(" "))
+        }
+        toml::Value::Table(table) => {
+            let entries: Vec<String> = table
+                .iter()
+                .map(|(key, value)| format!("{} = {};", nix_quote(key), toml_to_nix(value)))
+                .collect();
+            format!("{{ {} }}", entries.join(" "))
+        }
+    }
+}
+
+fn nix_quote(string: &str) -> String {
+    let mut out = String::with_capacity(string.len() + 2);
+    out.push('"');
+
+    let mut chars = string.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '$' if chars.peek() == Some(&'{') => out.push_str("\\$"),
+            other => out.push(other),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod find {
+        use super::*;
+
+        #[test]
+        fn none_without_fence() {
+            assert_eq!(None, Block::find("#!build cp $SRC $OUT").unwrap())
+        }
+
+        #[test]
+        fn finds_block() {
+            let block = Block::find("line\n# /// nix-script\n# a = 1\n#\n# b = 2\n# ///\nline")
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(1..=5, block.lines);
+            assert_eq!("a = 1\n\nb = 2", block.content);
+        }
+
+        #[test]
+        fn uses_prefix_of_opening_fence() {
+            let block = Block::find("// /// nix-script\n// a = 1\n// ///")
+                .unwrap()
+                .unwrap();
+
+            assert_eq!("a = 1", block.content);
+        }
+
+        #[test]
+        fn must_be_closed() {
+            let problem = Block::find("# /// nix-script\n# a = 1").unwrap_err();
+
+            assert!(problem.to_string().contains("is never closed"))
+        }
+
+        #[test]
+        fn lines_must_have_prefix() {
+            let problem = Block::find("# /// nix-script\na = 1\n# ///").unwrap_err();
+
+            assert!(problem.to_string().contains("does not start with `#`"))
+        }
+
+        #[test]
+        fn only_one_block_allowed() {
+            let problem =
+                Block::find("# /// nix-script\n# ///\n# /// nix-script\n# ///").unwrap_err();
+
+            assert!(problem.to_string().contains("second"))
+        }
+    }
+
+    mod remove_from {
+        use super::*;
+
+        #[test]
+        fn blanks_block_lines() {
+            let source = "a\n# /// nix-script\n# b = 1\n# ///\nc";
+            let block = Block::find(source).unwrap().unwrap();
+
+            assert_eq!("a\n\n\n\nc", block.remove_from(source))
+        }
+    }
+
+    mod fields {
+        use super::*;
+
+        fn fields(content: &str) -> HashMap<String, Vec<String>> {
+            Block {
+                lines: 0..=0,
+                content: content.to_string(),
+            }
+            .fields()
+            .unwrap()
+        }
+
+        #[test]
+        fn toml_strings() {
+            assert_eq!(
+                Some(&vec!["cp $SRC $OUT".to_string()]),
+                fields("build = \"cp $SRC $OUT\"").get("build")
+            )
+        }
+
+        #[test]
+        fn toml_lists() {
+            assert_eq!(
+                Some(&vec!["jq".to_string(), "curl".to_string()]),
+                fields("runtimeInputs = [\"jq\", \"curl\"]").get("runtimeInputs")
+            )
+        }
+
+        #[test]
+        fn toml_keeps_compound_expressions_together() {
+            assert_eq!(
+                Some(&vec![
+                    "(haskellPackages.ghcWithPackages (ps: [ ps.text ]))".to_string()
+                ]),
+                fields("buildInputs = [\"haskellPackages.ghcWithPackages (ps: [ ps.text ])\"]")
+                    .get("buildInputs")
+            )
+        }
+
+        #[test]
+        fn toml_quotes_files() {
+            assert_eq!(
+                Some(&vec!["'a b'".to_string(), "c".to_string()]),
+                fields("runtimeFiles = [\"a b\", \"c\"]").get("runtimeFiles")
+            )
+        }
+
+        #[test]
+        fn toml_tables_become_attrsets() {
+            assert_eq!(
+                Some(&vec!["{ \"allowUnfree\" = true; }".to_string()]),
+                fields("[nixpkgsConfig]\nallowUnfree = true").get("nixpkgsConfig")
+            )
+        }
+
+        #[test]
+        fn nix_strings() {
+            assert_eq!(
+                Some(&vec!["cp $SRC $OUT".to_string()]),
+                fields("{ build = \"cp $SRC $OUT\"; }").get("build")
+            )
+        }
+
+        #[test]
+        fn nix_lists() {
+            assert_eq!(
+                Some(&vec![
+                    "jq".to_string(),
+                    "(haskellPackages.ghcWithPackages (ps: [ ps.text ]))".to_string()
+                ]),
+                fields(
+                    "{ buildInputs = [ jq (haskellPackages.ghcWithPackages (ps: [ ps.text ])) ]; }"
+                )
+                .get("buildInputs")
+            )
+        }
+
+        #[test]
+        fn nix_attrsets_stay_raw() {
+            assert_eq!(
+                Some(&vec!["{ allowUnfree = true; }".to_string()]),
+                fields("{ nixpkgsConfig = { allowUnfree = true; }; }").get("nixpkgsConfig")
+            )
+        }
+
+        #[test]
+        fn nix_rejects_interpolation() {
+            let problem = Block {
+                lines: 0..=0,
+                content: "{ build = \"${x}\"; }".to_string(),
+            }
+            .fields()
+            .unwrap_err();
+
+            assert!(problem.to_string().contains("interpolation"))
+        }
+    }
+}
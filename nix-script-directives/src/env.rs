@@ -0,0 +1,199 @@
+use anyhow::{Context, Result};
+
+/// An environment variable set by `#!env` (at runtime) or `#!buildEnv` (while
+/// building). The forms mirror `makeWrapper`:
+///
+/// - `KEY=VALUE` sets `KEY`
+/// - `--prefix KEY SEP VALUE` puts `VALUE` in front of `KEY`, separated by
+///   `SEP` if `KEY` was already set
+/// - `--suffix KEY SEP VALUE` does the same at the end
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum EnvVar {
+    Set {
+        name: String,
+        value: String,
+    },
+    Prefix {
+        name: String,
+        separator: String,
+        value: String,
+    },
+    Suffix {
+        name: String,
+        separator: String,
+        value: String,
+    },
+}
+
+impl EnvVar {
+    /// Parse a directive line, which is split like shell words and may
+    /// contain several variables (like `A=1 B=2`).
+    pub fn parse_line(line: &str) -> Result<Vec<Self>> {
+        let words = shell_words::split(line).context("could not split environment variables")?;
+        let mut words = words.into_iter();
+        let mut out = Vec::new();
+
+        while let Some(word) = words.next() {
+            let var = match word.as_str() {
+                "--prefix" | "--suffix" => {
+                    let mut next = |what: &str| {
+                        words
+                            .next()
+                            .with_context(|| format!("`{word}` needs a {what}"))
+                    };
+                    let name = next("variable name")?;
+                    let separator = next("separator")?;
+                    let value = next("value")?;
+
+                    if word == "--prefix" {
+                        EnvVar::Prefix {
+                            name,
+                            separator,
+                            value,
+                        }
+                    } else {
+                        EnvVar::Suffix {
+                            name,
+                            separator,
+                            value,
+                        }
+                    }
+                }
+                _ => match word.split_once('=') {
+                    Some((name, value)) => EnvVar::Set {
+                        name: name.to_owned(),
+                        value: value.to_owned(),
+                    },
+                    None => anyhow::bail!(
+                        "expected `KEY=VALUE`, `--prefix KEY SEP VALUE` or `--suffix KEY SEP VALUE`, but got `{}`",
+                        word
+                    ),
+                },
+            };
+
+            validate_name(var.name())?;
+            out.push(var);
+        }
+
+        Ok(out)
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            EnvVar::Set { name, .. }
+            | EnvVar::Prefix { name, .. }
+            | EnvVar::Suffix { name, .. } => name,
+        }
+    }
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let valid = match chars.next() {
+        Some(first) => {
+            (first.is_ascii_alphabetic() || first == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        None => false,
+    };
+
+    if !valid {
+        anyhow::bail!("`{}` is not a valid environment variable name", name)
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod parse_line {
+        use super::*;
+
+        #[test]
+        fn set() {
+            assert_eq!(
+                vec![EnvVar::Set {
+                    name: "A".into(),
+                    value: "1".into()
+                }],
+                EnvVar::parse_line("A=1").unwrap()
+            )
+        }
+
+        #[test]
+        fn set_many() {
+            assert_eq!(2, EnvVar::parse_line("A=1 B=2").unwrap().len())
+        }
+
+        #[test]
+        fn set_quoted() {
+            assert_eq!(
+                vec![EnvVar::Set {
+                    name: "A".into(),
+                    value: "hello world".into()
+                }],
+                EnvVar::parse_line("A='hello world'").unwrap()
+            )
+        }
+
+        #[test]
+        fn set_empty() {
+            assert_eq!(
+                vec![EnvVar::Set {
+                    name: "A".into(),
+                    value: "".into()
+                }],
+                EnvVar::parse_line("A=").unwrap()
+            )
+        }
+
+        #[test]
+        fn prefix() {
+            assert_eq!(
+                vec![EnvVar::Prefix {
+                    name: "PATH".into(),
+                    separator: ":".into(),
+                    value: "/opt/bin".into()
+                }],
+                EnvVar::parse_line("--prefix PATH : /opt/bin").unwrap()
+            )
+        }
+
+        #[test]
+        fn suffix() {
+            assert_eq!(
+                vec![EnvVar::Suffix {
+                    name: "PATH".into(),
+                    separator: ":".into(),
+                    value: "/opt/bin".into()
+                }],
+                EnvVar::parse_line("--suffix PATH : /opt/bin").unwrap()
+            )
+        }
+
+        #[test]
+        fn prefix_needs_all_arguments() {
+            let problem = EnvVar::parse_line("--prefix PATH :").unwrap_err();
+
+            assert_eq!("`--prefix` needs a value", problem.to_string())
+        }
+
+        #[test]
+        fn rejects_bare_words() {
+            assert!(EnvVar::parse_line("A").is_err())
+        }
+
+        #[test]
+        fn rejects_invalid_names() {
+            let problem = EnvVar::parse_line("1A=1").unwrap_err();
+
+            assert_eq!(
+                "`1A` is not a valid environment variable name",
+                problem.to_string()
+            )
+        }
+    }
+}
@@ -4,6 +4,7 @@ use rnix::ast::List;
 use rnix::{Root, SyntaxKind, SyntaxNode};
 use rowan::ast::AstNode;
 use std::cmp::Ordering;
+use std::collections::BTreeSet;
 use std::fmt::{self, Display};
 use std::str::FromStr;
 
@@ -39,6 +40,65 @@ impl Expr {
     pub fn is_leaf(&self) -> bool {
         self.kind() == SyntaxKind::NODE_IDENT
     }
+
+    /// The identifier this expression starts from, like `python3` in
+    /// `python3.withPackages (ps: [ ps.requests ])`. Only looks through
+    /// function applications, attribute selections and parentheses.
+    pub fn head(&self) -> Option<String> {
+        let mut node = self.parsed.clone();
+
+        loop {
+            match node.kind() {
+                SyntaxKind::NODE_IDENT => return Some(node.to_string()),
+                SyntaxKind::NODE_APPLY | SyntaxKind::NODE_SELECT | SyntaxKind::NODE_PAREN => {
+                    node = node.first_child()?
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    /// The names this expression takes from its surroundings, like `lib` and
+    /// `zlib` in `lib.makeLibraryPath [ zlib ]`. Attribute names and names
+    /// bound by function arguments or `let` are left out.
+    pub fn identifiers(&self) -> BTreeSet<String> {
+        let mut used = BTreeSet::new();
+        let mut bound = BTreeSet::new();
+
+        for node in self.parsed.descendants() {
+            if node.kind() != SyntaxKind::NODE_IDENT {
+                continue;
+            }
+
+            match node.parent().map(|parent| parent.kind()) {
+                Some(SyntaxKind::NODE_ATTRPATH) => {
+                    let in_let = node
+                        .parent()
+                        .and_then(|attrpath| attrpath.parent())
+                        .and_then(|binding| binding.parent())
+                        .is_some_and(|scope| scope.kind() == SyntaxKind::NODE_LET_IN);
+                    if in_let {
+                        bound.insert(node.to_string());
+                    }
+                }
+                Some(
+                    SyntaxKind::NODE_IDENT_PARAM
+                    | SyntaxKind::NODE_PAT_ENTRY
+                    | SyntaxKind::NODE_PAT_BIND,
+                ) => {
+                    bound.insert(node.to_string());
+                }
+                _ => {
+                    used.insert(node.to_string());
+                }
+            }
+        }
+
+        used.retain(|name| {
+            !bound.contains(name) && !["builtins", "true", "false", "null"].contains(&name.as_str())
+        });
+        used
+    }
 }
 
 impl Display for Expr {
@@ -175,6 +235,42 @@ mod tests {
         }
     }
 
+    mod identifiers {
+        use super::*;
+
+        fn identifiers(source: &str) -> Vec<String> {
+            Expr::from_str(source)
+                .unwrap()
+                .identifiers()
+                .into_iter()
+                .collect()
+        }
+
+        #[test]
+        fn leaves_out_attribute_names() {
+            assert_eq!(
+                vec!["lib", "zlib"],
+                identifiers("lib.makeLibraryPath [ zlib ]")
+            )
+        }
+
+        #[test]
+        fn leaves_out_bound_names() {
+            assert_eq!(
+                vec!["python3"],
+                identifiers("let p = python3; in p.withPackages (ps: [ ps.requests ])")
+            )
+        }
+
+        #[test]
+        fn leaves_out_builtins() {
+            assert_eq!(
+                vec!["jq"],
+                identifiers("builtins.toString (if true then jq else null)")
+            )
+        }
+    }
+
     mod is_leaf {
         use super::*;
 
@@ -192,6 +288,27 @@ mod tests {
         }
     }
 
+    mod head {
+        use super::*;
+
+        #[test]
+        fn ident() {
+            assert_eq!(Some("jq".into()), Expr::from_str("jq").unwrap().head())
+        }
+
+        #[test]
+        fn apply_and_select() {
+            let expr = Expr::from_str("(python3.withPackages (ps: [ ps.requests ]))").unwrap();
+            assert_eq!(Some("python3".into()), expr.head())
+        }
+
+        #[test]
+        fn with_has_none() {
+            let expr = Expr::from_str("with haskellPackages; ghc").unwrap();
+            assert_eq!(None, expr.head())
+        }
+    }
+
     mod display {
         use super::*;
 
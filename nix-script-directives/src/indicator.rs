@@ -0,0 +1,115 @@
+use std::path::Path;
+
+/// The indicator directive lines start with, regardless of language.
+pub const DEFAULT: &str = "#!";
+
+/// Guess which indicators directive lines in a script start with: always
+/// [`DEFAULT`], plus the line comment of the script's language followed by
+/// `nix-script:` (like `# nix-script: build ...`) if we can tell what that is
+/// from the file extension or the interpreter in the `#!` line. Languages
+/// whose compilers reject `#!` lines after the first can then use comments
+/// for directives instead. The marker keeps ordinary comments that happen to
+/// start with a directive name (`# build the list of files`) from being read
+/// as directives.
+pub fn infer(filename: &Path, source: &str) -> Vec<&'static str> {
+    let comment = filename
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .and_then(from_extension)
+        .or_else(|| source.lines().next().and_then(from_shebang));
+
+    match comment {
+        Some(comment) => vec![DEFAULT, comment],
+        None => vec![DEFAULT],
+    }
+}
+
+fn from_extension(extension: &str) -> Option<&'static str> {
+    match extension {
+        "c" | "h" | "cc" | "cpp" | "cxx" | "hpp" | "rs" | "go" => Some("// nix-script:"),
+        "hs" | "lua" => Some("-- nix-script:"),
+        "py" => Some("# nix-script:"),
+        _ => None,
+    }
+}
+
+fn from_shebang(line: &str) -> Option<&'static str> {
+    let mut words = line.strip_prefix("#!")?.split_whitespace();
+
+    let mut interpreter = Path::new(words.next()?).file_name()?.to_str()?;
+    if interpreter == "env" {
+        interpreter = words.find(|word| !word.starts_with('-'))?;
+    }
+
+    match interpreter {
+        "runghc" | "runhaskell" | "nix-script-haskell" => Some("-- nix-script:"),
+        "lua" | "luajit" => Some("-- nix-script:"),
+        python if python.starts_with("python") => Some("# nix-script:"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod infer {
+        use super::*;
+
+        #[test]
+        fn default_for_unknown() {
+            assert_eq!(vec!["#!"], infer(Path::new("script.sh"), ""))
+        }
+
+        #[test]
+        fn from_extension() {
+            assert_eq!(
+                vec!["#!", "// nix-script:"],
+                infer(Path::new("main.rs"), "")
+            );
+            assert_eq!(
+                vec!["#!", "-- nix-script:"],
+                infer(Path::new("Main.hs"), "")
+            );
+            assert_eq!(
+                vec!["#!", "# nix-script:"],
+                infer(Path::new("script.py"), "")
+            );
+        }
+
+        #[test]
+        fn from_env_shebang() {
+            assert_eq!(
+                vec!["#!", "# nix-script:"],
+                infer(Path::new("script"), "#!/usr/bin/env python3\n")
+            )
+        }
+
+        #[test]
+        fn from_env_shebang_with_flags() {
+            assert_eq!(
+                vec!["#!", "-- nix-script:"],
+                infer(
+                    Path::new("script"),
+                    "#!/usr/bin/env -S nix-script-haskell\n"
+                )
+            )
+        }
+
+        #[test]
+        fn from_direct_shebang() {
+            assert_eq!(
+                vec!["#!", "-- nix-script:"],
+                infer(Path::new("script"), "#!/usr/bin/lua\n")
+            )
+        }
+
+        #[test]
+        fn extension_wins_over_shebang() {
+            assert_eq!(
+                vec!["#!", "// nix-script:"],
+                infer(Path::new("main.go"), "#!/usr/bin/env python3\n")
+            )
+        }
+    }
+}
@@ -1,9 +1,16 @@
 #[warn(clippy::cargo)]
+mod block;
+pub mod env;
 pub mod expr;
+pub mod indicator;
 mod parser;
+pub mod wrapper;
 
+use crate::env::EnvVar;
 use crate::expr::Expr;
+use crate::wrapper::WrapperArg;
 use anyhow::{Context, Result};
+use block::Block;
 use core::hash::{Hash, Hasher};
 use parser::Parser;
 use rnix::SyntaxKind;
@@ -12,48 +19,255 @@ use std::path::Path;
 use std::path::PathBuf;
 use std::str::FromStr;
 
+/// The directives `nix-script` itself understands. Wrapper scripts may use
+/// others, which end up in [`Directives::all`].
+const KEYS: &[&str] = &[
+    "build",
+    "check",
+    "installCommand",
+    "preBuild",
+    "postBuild",
+    "preInstall",
+    "postInstall",
+    "buildRoot",
+    "buildInputs",
+    "interpreter",
+    "runtimeInputs",
+    "runtimeFiles",
+    "buildFiles",
+    "binaries",
+    "nixpkgsConfig",
+    "include",
+    "env",
+    "buildEnv",
+    "wrapperArgs",
+    "allowRead",
+    "allowWrite",
+    "allowNetwork",
+    "allowEnv",
+];
+
 #[derive(Debug, serde::Serialize)]
 pub struct Directives {
     pub build_command: Option<String>,
+    pub check_command: Option<String>,
+    pub install_command: Option<String>,
+    pub pre_build: Option<String>,
+    pub post_build: Option<String>,
+    pub pre_install: Option<String>,
+    pub post_install: Option<String>,
     pub build_root: Option<PathBuf>,
     pub build_inputs: Vec<Expr>,
     pub interpreter: Option<String>,
     pub runtime_inputs: Vec<Expr>,
     pub runtime_files: Vec<PathBuf>,
+    pub build_files: Vec<PathBuf>,
+    /// Extra executables the build puts in `bin`, next to the script itself.
+    pub binaries: Vec<String>,
     pub nixpkgs_config: Option<Expr>,
+    pub env: Vec<EnvVar>,
+    pub build_env: Vec<EnvVar>,
+    pub wrapper_args: Vec<WrapperArg>,
+    /// Paths the script may read when run in a sandbox, besides the Nix
+    /// store paths it depends on.
+    pub allow_read: Vec<PathBuf>,
+    /// Paths the script may read and write when run in a sandbox.
+    pub allow_write: Vec<PathBuf>,
+    /// Whether the script may use the network when run in a sandbox.
+    pub allow_network: bool,
+    /// Environment variables passed on to the script when run in a sandbox.
+    pub allow_env: Vec<String>,
+    /// The indicators directive lines were read with.
+    pub indicators: Vec<String>,
+    /// Files spliced in with `#!include`, as canonical paths.
+    pub includes: Vec<PathBuf>,
     pub all: HashMap<String, Vec<String>>,
 }
 
 impl Directives {
-    pub fn from_file(indicator: &str, filename: &Path) -> Result<Self> {
+    /// Read directives from a script. If no indicator is given, we infer
+    /// them from the script (see [`indicator::infer`].)
+    pub fn from_file(indicator: Option<&str>, filename: &Path) -> Result<Self> {
         let source = std::fs::read_to_string(filename).context("could not read source")?;
-        Self::parse(indicator, &source)
+
+        let indicators = match indicator {
+            Some(indicator) => vec![indicator],
+            None => indicator::infer(filename, &source),
+        };
+        log::debug!("reading directives with indicators {indicators:?}");
+
+        let mut directives = Self::parse(&indicators, &source, filename)?;
+        directives.indicators = indicators.iter().map(|i| i.to_string()).collect();
+
+        Ok(directives)
     }
 
-    fn parse(indicator: &str, source: &str) -> Result<Self> {
-        let parser = Parser::new(indicator).context("could not construct parser")?;
-        let fields = parser.parse(source);
+    fn parse(indicators: &[&str], source: &str, path: &Path) -> Result<Self> {
+        let mut stack = vec![path.canonicalize().unwrap_or_else(|_| path.to_owned())];
+        let mut includes = Vec::new();
+
+        let fields = Self::collect(indicators, source, path, &mut stack, &mut includes)?;
 
-        Self::from_directives(fields)
+        let mut directives = Self::from_directives(
+            fields
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.iter().map(|s| s.as_str()).collect()))
+                .collect(),
+        )?;
+        directives.includes = includes;
+
+        Ok(directives)
+    }
+
+    /// Get the fields of a source file, with the fields of any files it
+    /// `#!include`s spliced in before its own. Paths are relative to the
+    /// including file. `stack` holds the files we are currently including
+    /// (to detect cycles) and `includes` all files included so far (so a file
+    /// included twice only counts once.)
+    fn collect(
+        indicators: &[&str],
+        source: &str,
+        path: &Path,
+        stack: &mut Vec<PathBuf>,
+        includes: &mut Vec<PathBuf>,
+    ) -> Result<HashMap<String, Vec<String>>> {
+        let own = Self::fields(indicators, source)?;
+        let mut out: HashMap<String, Vec<String>> = HashMap::new();
+
+        for line in own.get("include").into_iter().flatten() {
+            for include in
+                shell_words::split(line).context("could not split `include` into paths")?
+            {
+                let relative = path
+                    .parent()
+                    .unwrap_or_else(|| Path::new("."))
+                    .join(&include);
+                let canonical = relative.canonicalize().with_context(|| {
+                    format!("could not find included file {}", relative.display())
+                })?;
+
+                if stack.contains(&canonical) {
+                    let cycle: Vec<String> = stack
+                        .iter()
+                        .chain(std::iter::once(&canonical))
+                        .map(|p| p.display().to_string())
+                        .collect();
+                    anyhow::bail!("found an include cycle: {}", cycle.join(" -> "))
+                }
+
+                if includes.contains(&canonical) {
+                    log::debug!(
+                        "skipping {} because it was already included",
+                        canonical.display()
+                    );
+                    continue;
+                }
+                includes.push(canonical.clone());
+
+                let included_source = std::fs::read_to_string(&canonical).with_context(|| {
+                    format!("could not read included file {}", canonical.display())
+                })?;
+
+                stack.push(canonical.clone());
+                let included =
+                    Self::collect(indicators, &included_source, &canonical, stack, includes)
+                        .with_context(|| {
+                            format!("could not read directives from {}", canonical.display())
+                        })?;
+                stack.pop();
+
+                for (key, values) in included {
+                    out.entry(key).or_default().extend(values);
+                }
+            }
+        }
+
+        for (key, values) in own {
+            out.entry(key).or_default().extend(values);
+        }
+
+        Ok(out)
+    }
+
+    /// Get the fields of a single source file, either from directive lines or
+    /// from a `/// nix-script` block.
+    fn fields(indicators: &[&str], source: &str) -> Result<HashMap<String, Vec<String>>> {
+        let parser = Parser::new(indicators).context("could not construct parser")?;
+
+        let block = match Block::find(source).context("could not read `/// nix-script` block")? {
+            Some(block) => block,
+            None => return Ok(to_owned_fields(parser.parse(source))),
+        };
+
+        let rest = block.remove_from(source);
+        let fields = parser.parse(&rest);
+        if let Some(key) = fields.keys().find(|key| KEYS.contains(key)) {
+            anyhow::bail!(
+                "found both a `/// nix-script` block and a `{}` directive line, but only one style is allowed per file",
+                key
+            )
+        }
+
+        let mut fields = to_owned_fields(fields);
+        let block_fields = block
+            .fields()
+            .context("could not parse `/// nix-script` block")?;
+        for (key, values) in block_fields {
+            fields.entry(key).or_default().extend(values);
+        }
+
+        Ok(fields)
     }
 
     fn from_directives(fields: HashMap<&str, Vec<&str>>) -> Result<Self> {
         let build_command = Self::once("build", &fields)?.map(|s| s.to_owned());
+        let check_command = Self::once("check", &fields)?.map(|s| s.to_owned());
+        let install_command = Self::once("installCommand", &fields)?.map(|s| s.to_owned());
+        let pre_build = Self::script("preBuild", &fields);
+        let post_build = Self::script("postBuild", &fields);
+        let pre_install = Self::script("preInstall", &fields);
+        let post_install = Self::script("postInstall", &fields);
         let build_root = Self::once("buildRoot", &fields)?.map(PathBuf::from);
         let build_inputs = Self::exprs("buildInputs", &fields)?;
         let interpreter = Self::once("interpreter", &fields)?.map(|s| s.to_owned());
         let runtime_inputs = Self::exprs("runtimeInputs", &fields)?;
-        let runtime_files = Self::files("runtimeFiles", &fields);
+        let runtime_files = Self::files("runtimeFiles", &fields)?;
+        let build_files = Self::files("buildFiles", &fields)?;
+        let binaries = Self::binary_names("binaries", &fields)?;
         let nixpkgs_config = Self::once_attrset("nixpkgsConfig", &fields)?;
+        let env = Self::env_vars("env", &fields)?;
+        let build_env = Self::env_vars("buildEnv", &fields)?;
+        let wrapper_args = Self::wrapper_args("wrapperArgs", &fields)?;
+        let allow_read = Self::files("allowRead", &fields)?;
+        let allow_write = Self::files("allowWrite", &fields)?;
+        let allow_network = Self::flag("allowNetwork", &fields)?;
+        let allow_env = Self::env_names("allowEnv", &fields)?;
 
         Ok(Directives {
             build_command,
+            check_command,
+            install_command,
+            pre_build,
+            post_build,
+            pre_install,
+            post_install,
             build_root,
             build_inputs,
             interpreter,
             runtime_inputs,
             runtime_files,
+            build_files,
+            binaries,
             nixpkgs_config,
+            env,
+            build_env,
+            wrapper_args,
+            allow_read,
+            allow_write,
+            allow_network,
+            allow_env,
+            indicators: Vec::new(),
+            includes: Vec::new(),
             all: fields
                 .iter()
                 .map(|(k, v)| (k.to_string(), v.iter().map(|s| s.to_string()).collect()))
@@ -77,6 +291,27 @@ impl Directives {
         }
     }
 
+    /// A shell snippet that may span several directive lines, which we keep
+    /// as separate lines.
+    fn script(field: &str, fields: &HashMap<&str, Vec<&str>>) -> Option<String> {
+        fields.get(field).map(|lines| lines.join("\n"))
+    }
+
+    /// A directive that is on when given without a value. It can also be set
+    /// to `true` or `false` explicitly, and the last one wins.
+    fn flag(field: &str, fields: &HashMap<&str, Vec<&str>>) -> Result<bool> {
+        match fields.get(field).and_then(|values| values.last()) {
+            None => Ok(false),
+            Some(&"") | Some(&"true") => Ok(true),
+            Some(&"false") => Ok(false),
+            Some(other) => anyhow::bail!(
+                "`{}` directive should have no value, `true` or `false`, but was `{}`",
+                field,
+                other
+            ),
+        }
+    }
+
     fn once_attrset<'field>(
         field: &'field str,
         fields: &HashMap<&'field str, Vec<&'field str>>,
@@ -111,14 +346,96 @@ impl Directives {
         }
     }
 
+    /// Split paths like a shell would, so they can be quoted or escaped to
+    /// contain spaces. Glob patterns are kept as they are; we can only expand
+    /// them once we know the build root.
     fn files<'field>(
         field: &'field str,
         fields: &HashMap<&'field str, Vec<&'field str>>,
-    ) -> Vec<PathBuf> {
-        match fields.get(field) {
-            None => Vec::new(),
-            Some(lines) => lines.join(" ").split(' ').map(PathBuf::from).collect(),
+    ) -> Result<Vec<PathBuf>> {
+        let mut out = Vec::new();
+
+        for line in fields.get(field).into_iter().flatten() {
+            let words = shell_words::split(line)
+                .with_context(|| format!("could not split `{field}` into paths"))?;
+            out.extend(words.into_iter().map(PathBuf::from));
+        }
+
+        Ok(out)
+    }
+
+    fn binary_names<'field>(
+        field: &'field str,
+        fields: &HashMap<&'field str, Vec<&'field str>>,
+    ) -> Result<Vec<String>> {
+        let mut out = Vec::new();
+
+        for line in fields.get(field).into_iter().flatten() {
+            let words = shell_words::split(line)
+                .with_context(|| format!("could not split `{field}` into names"))?;
+
+            for word in words {
+                validate_binary_name(&word)?;
+                if !out.contains(&word) {
+                    out.push(word);
+                }
+            }
+        }
+
+        Ok(out)
+    }
+
+    fn env_names<'field>(
+        field: &'field str,
+        fields: &HashMap<&'field str, Vec<&'field str>>,
+    ) -> Result<Vec<String>> {
+        let mut out = Vec::new();
+
+        for line in fields.get(field).into_iter().flatten() {
+            let words = shell_words::split(line)
+                .with_context(|| format!("could not split `{field}` into names"))?;
+
+            for word in words {
+                validate_env_name(&word)?;
+                if !out.contains(&word) {
+                    out.push(word);
+                }
+            }
         }
+
+        Ok(out)
+    }
+
+    fn env_vars<'field>(
+        field: &'field str,
+        fields: &HashMap<&'field str, Vec<&'field str>>,
+    ) -> Result<Vec<EnvVar>> {
+        let mut out = Vec::new();
+
+        for line in fields.get(field).into_iter().flatten() {
+            out.extend(
+                EnvVar::parse_line(line)
+                    .with_context(|| format!("could not parse `{field}` directive"))?,
+            );
+        }
+
+        Ok(out)
+    }
+
+    fn wrapper_args<'field>(
+        field: &'field str,
+        fields: &HashMap<&'field str, Vec<&'field str>>,
+    ) -> Result<Vec<WrapperArg>> {
+        let mut out = Vec::new();
+
+        for line in fields.get(field).into_iter().flatten() {
+            out.extend(
+                WrapperArg::parse_line(line)
+                    .with_context(|| format!("could not parse `{field}` directive"))?,
+            );
+        }
+
+        Ok(out)
     }
 
     pub fn maybe_override_build_command(&mut self, maybe_new: &Option<String>) {
@@ -127,6 +444,22 @@ impl Directives {
         }
     }
 
+    pub fn maybe_override_check_command(&mut self, maybe_new: &Option<String>) {
+        if maybe_new.is_some() {
+            maybe_new.clone_into(&mut self.check_command)
+        }
+    }
+
+    pub fn maybe_override_install_command(&mut self, maybe_new: &Option<String>) {
+        if maybe_new.is_some() {
+            maybe_new.clone_into(&mut self.install_command)
+        }
+    }
+
+    pub fn skip_checks(&mut self) {
+        self.check_command = None
+    }
+
     pub fn merge_build_inputs(&mut self, new: &[String]) -> Result<()> {
         for item in new {
             let parsed = (item).parse().context("could not parse build input")?;
@@ -163,6 +496,83 @@ impl Directives {
         }
     }
 
+    pub fn merge_build_files(&mut self, new: &[PathBuf]) {
+        for item in new {
+            if !self.build_files.contains(item) {
+                self.build_files.push(item.clone())
+            }
+        }
+    }
+
+    pub fn merge_binaries(&mut self, new: &[String]) -> Result<()> {
+        for item in new {
+            validate_binary_name(item)?;
+            if !self.binaries.contains(item) {
+                self.binaries.push(item.clone())
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn merge_env(&mut self, new: &[String]) -> Result<()> {
+        for line in new {
+            self.env
+                .extend(EnvVar::parse_line(line).context("could not parse environment variable")?);
+        }
+
+        Ok(())
+    }
+
+    pub fn merge_build_env(&mut self, new: &[String]) -> Result<()> {
+        for line in new {
+            self.build_env
+                .extend(EnvVar::parse_line(line).context("could not parse environment variable")?);
+        }
+
+        Ok(())
+    }
+
+    pub fn merge_wrapper_args(&mut self, new: &[String]) -> Result<()> {
+        for line in new {
+            self.wrapper_args
+                .extend(WrapperArg::parse_line(line).context("could not parse wrapper arguments")?);
+        }
+
+        Ok(())
+    }
+
+    pub fn merge_allow_read(&mut self, new: &[PathBuf]) {
+        for item in new {
+            if !self.allow_read.contains(item) {
+                self.allow_read.push(item.clone())
+            }
+        }
+    }
+
+    pub fn merge_allow_write(&mut self, new: &[PathBuf]) {
+        for item in new {
+            if !self.allow_write.contains(item) {
+                self.allow_write.push(item.clone())
+            }
+        }
+    }
+
+    pub fn allow_network(&mut self) {
+        self.allow_network = true
+    }
+
+    pub fn merge_allow_env(&mut self, new: &[String]) -> Result<()> {
+        for item in new {
+            validate_env_name(item)?;
+            if !self.allow_env.contains(item) {
+                self.allow_env.push(item.clone())
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn override_nixpkgs_config(&mut self, expr: &Expr) -> Result<()> {
         match expr.kind() {
             SyntaxKind::NODE_ATTR_SET => self.nixpkgs_config = Some(expr.clone()),
@@ -176,41 +586,322 @@ impl Directives {
     }
 }
 
+/// Binaries live directly in `bin`, so their names can't point anywhere else.
+fn validate_binary_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        anyhow::bail!("`{}` is not a valid binary name", name)
+    }
+
+    Ok(())
+}
+
+fn validate_env_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains('=') {
+        anyhow::bail!("`{}` is not a valid environment variable name", name)
+    }
+
+    Ok(())
+}
+
+fn to_owned_fields(fields: HashMap<&str, Vec<&str>>) -> HashMap<String, Vec<String>> {
+    fields
+        .into_iter()
+        .map(|(k, v)| (k.to_owned(), v.into_iter().map(|s| s.to_owned()).collect()))
+        .collect()
+}
+
 impl Hash for Directives {
     fn hash<H: Hasher>(&self, hasher: &mut H) {
-        if let Some(build_command) = &self.build_command {
-            hasher.write(build_command.as_ref())
+        for (name, script) in [
+            ("build", &self.build_command),
+            ("check", &self.check_command),
+            ("installCommand", &self.install_command),
+            ("preBuild", &self.pre_build),
+            ("postBuild", &self.post_build),
+            ("preInstall", &self.pre_install),
+            ("postInstall", &self.post_install),
+        ] {
+            hash_str(hasher, name, script.as_deref());
         }
 
-        for input in &self.build_inputs {
-            input.hash(hasher)
+        hash_list(hasher, "buildInputs", &self.build_inputs);
+
+        hash_str(hasher, "interpreter", self.interpreter.as_deref());
+
+        hash_list(hasher, "runtimeInputs", &self.runtime_inputs);
+
+        hash_str(
+            hasher,
+            "buildRoot",
+            self.build_root
+                .as_ref()
+                .map(|build_root| build_root.display().to_string())
+                .as_deref(),
+        );
+
+        hash_list(hasher, "runtimeFiles", &self.runtime_files);
+        hash_list(hasher, "buildFiles", &self.build_files);
+
+        hash_list(hasher, "binaries", &self.binaries);
+
+        hash_str(
+            hasher,
+            "nixpkgsConfig",
+            self.nixpkgs_config
+                .as_ref()
+                .map(|config| config.to_string())
+                .as_deref(),
+        );
+
+        hash_list(hasher, "env", &self.env);
+        hash_list(hasher, "buildEnv", &self.build_env);
+        hash_list(hasher, "wrapperArgs", &self.wrapper_args);
+
+        // The `allow*` directives only change how we run the built script,
+        // not what we build, so they stay out of the hash.
+    }
+}
+
+/// Hash an optional string with its name and length in front, so text can't
+/// move between neighbouring directives without changing the hash.
+fn hash_str<H: Hasher>(hasher: &mut H, name: &str, value: Option<&str>) {
+    if let Some(value) = value {
+        hasher.write(name.as_ref());
+        hasher.write_usize(value.len());
+        hasher.write(value.as_ref());
+    }
+}
+
+/// Hash a list with its name and length in front, so the same items in a
+/// different list (or split differently between neighbouring lists) hash
+/// differently.
+fn hash_list<H: Hasher, T: Hash>(hasher: &mut H, name: &str, items: &[T]) {
+    hasher.write(name.as_ref());
+    hasher.write_usize(items.len());
+    for item in items {
+        item.hash(hasher)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod parse {
+        use super::*;
+
+        #[test]
+        fn reads_toml_block() {
+            let directives = Directives::parse(
+                &["#!"],
+                "#!/usr/bin/env nix-script\n# /// nix-script\n# build = \"cp $SRC $OUT\"\n# runtimeInputs = [\"jq\"]\n# ///\n",
+                Path::new("script"),
+            )
+            .unwrap();
+
+            assert_eq!(Some("cp $SRC $OUT".to_string()), directives.build_command);
+            assert_eq!(
+                vec!["jq".parse::<Expr>().unwrap()],
+                directives.runtime_inputs
+            );
+        }
+
+        #[test]
+        fn reads_nix_block() {
+            let directives = Directives::parse(
+                &["#!"],
+                "// /// nix-script\n// { build = \"cp $SRC $OUT\"; nixpkgsConfig = { }; }\n// ///\n",
+                Path::new("script"),
+            )
+            .unwrap();
+
+            assert_eq!(Some("cp $SRC $OUT".to_string()), directives.build_command);
+            assert!(directives.nixpkgs_config.is_some());
+        }
+
+        #[test]
+        fn keeps_shebang_with_block() {
+            let directives = Directives::parse(
+                &["#!"],
+                "#!/usr/bin/env nix-script\n# /// nix-script\n# build = \"cp $SRC $OUT\"\n# ///\n",
+                Path::new("script"),
+            )
+            .unwrap();
+
+            assert!(directives.all.contains_key("/usr/bin/env"));
+        }
+
+        #[test]
+        fn reads_lines_with_any_indicator() {
+            let directives = Directives::parse(
+                &["#!", "--"],
+                "#!build cp $SRC $OUT\n-- runtimeInputs jq\n",
+                Path::new("script"),
+            )
+            .unwrap();
+
+            assert_eq!(Some("cp $SRC $OUT".to_string()), directives.build_command);
+            assert_eq!(
+                vec!["jq".parse::<Expr>().unwrap()],
+                directives.runtime_inputs
+            );
         }
 
-        if let Some(interpreter) = &self.interpreter {
-            hasher.write(interpreter.as_ref())
+        #[test]
+        fn cannot_mix_block_and_lines() {
+            let problem = Directives::parse(
+                &["#!"],
+                "#!build cp $SRC $OUT\n# /// nix-script\n# runtimeInputs = [\"jq\"]\n# ///\n",
+                Path::new("script"),
+            )
+            .unwrap_err();
+
+            assert!(problem.to_string().contains("only one style"))
         }
+    }
+
+    mod include {
+        use super::*;
+        use tempfile::tempdir;
 
-        for input in &self.runtime_inputs {
-            input.hash(hasher)
+        fn write(root: &Path, path: &str, contents: &str) -> PathBuf {
+            let full = root.join(path);
+            std::fs::create_dir_all(full.parent().unwrap()).unwrap();
+            std::fs::write(&full, contents).unwrap();
+            full
         }
 
-        if let Some(build_root) = &self.build_root {
-            hasher.write(build_root.display().to_string().as_ref())
+        #[test]
+        fn splices_included_directives() {
+            let root = tempdir().unwrap();
+            let common = write(
+                root.path(),
+                "common.directives",
+                "#!build cp $SRC $OUT\n#!runtimeInputs jq\n",
+            );
+            let script = write(
+                root.path(),
+                "script.sh",
+                "#!include common.directives\n#!runtimeInputs curl\n",
+            );
+
+            let directives = Directives::from_file(None, &script).unwrap();
+
+            assert_eq!(Some("cp $SRC $OUT".to_string()), directives.build_command);
+            assert_eq!(
+                vec!["jq".parse::<Expr>().unwrap(), "curl".parse().unwrap()],
+                directives.runtime_inputs
+            );
+            assert_eq!(vec![common.canonicalize().unwrap()], directives.includes);
         }
 
-        for file in &self.runtime_files {
-            hasher.write(file.display().to_string().as_ref())
+        #[test]
+        fn resolves_relative_to_including_file() {
+            let root = tempdir().unwrap();
+            write(
+                root.path(),
+                "common/base.directives",
+                "#!runtimeInputs jq\n",
+            );
+            write(
+                root.path(),
+                "common/all.directives",
+                "#!include base.directives\n",
+            );
+            let script = write(
+                root.path(),
+                "scripts/script.sh",
+                "#!include ../common/all.directives\n",
+            );
+
+            let directives = Directives::from_file(None, &script).unwrap();
+
+            assert_eq!(
+                vec!["jq".parse::<Expr>().unwrap()],
+                directives.runtime_inputs
+            );
+            assert_eq!(2, directives.includes.len());
         }
 
-        if let Some(nixpkgs_config) = &self.nixpkgs_config {
-            hasher.write(nixpkgs_config.to_string().as_ref())
+        #[test]
+        fn detects_cycles() {
+            let root = tempdir().unwrap();
+            write(root.path(), "a.directives", "#!include b.directives\n");
+            write(root.path(), "b.directives", "#!include a.directives\n");
+            let script = write(root.path(), "script.sh", "#!include a.directives\n");
+
+            let problem = Directives::from_file(None, &script).unwrap_err();
+
+            assert!(format!("{problem:#}").contains("include cycle"))
+        }
+
+        #[test]
+        fn includes_each_file_once() {
+            let root = tempdir().unwrap();
+            write(root.path(), "base.directives", "#!build cp $SRC $OUT\n");
+            write(root.path(), "a.directives", "#!include base.directives\n");
+            write(root.path(), "b.directives", "#!include base.directives\n");
+            let script = write(
+                root.path(),
+                "script.sh",
+                "#!include a.directives b.directives\n",
+            );
+
+            let directives = Directives::from_file(None, &script).unwrap();
+
+            assert_eq!(Some("cp $SRC $OUT".to_string()), directives.build_command);
+        }
+
+        #[test]
+        fn single_valued_directives_conflict() {
+            let root = tempdir().unwrap();
+            write(root.path(), "common.directives", "#!build cp $SRC $OUT\n");
+            let script = write(
+                root.path(),
+                "script.sh",
+                "#!include common.directives\n#!build mv $SRC $OUT\n",
+            );
+
+            let problem = Directives::from_file(None, &script).unwrap_err();
+
+            assert!(problem.to_string().contains("multiple `build` directives"))
+        }
+
+        #[test]
+        fn missing_file() {
+            let root = tempdir().unwrap();
+            let script = write(root.path(), "script.sh", "#!include nope.directives\n");
+
+            let problem = Directives::from_file(None, &script).unwrap_err();
+
+            assert!(problem.to_string().contains("could not find included file"))
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    mod from_file {
+        use super::*;
+        use tempfile::tempdir;
+
+        #[test]
+        fn ignores_ordinary_comments() {
+            let root = tempdir().unwrap();
+            let script = root.path().join("script.py");
+            std::fs::write(
+                &script,
+                "#!/usr/bin/env nix-script\n# nix-script: runtimeInputs python3\n# build the list of files\n# env vars we need\n",
+            )
+            .unwrap();
+
+            let directives = Directives::from_file(None, &script).unwrap();
+
+            assert_eq!(None, directives.build_command);
+            assert!(directives.env.is_empty());
+            assert_eq!(
+                vec!["python3".parse::<Expr>().unwrap()],
+                directives.runtime_inputs
+            );
+        }
+    }
 
     mod from_directives {
         use super::*;
@@ -223,6 +914,119 @@ mod tests {
             assert!(problem.to_string().contains("multiple `build` directives"),)
         }
 
+        #[test]
+        fn only_one_check_command_allowed() {
+            let problem = Directives::from_directives(HashMap::from([("check", vec!["a", "b"])]))
+                .unwrap_err();
+
+            assert!(problem.to_string().contains("multiple `check` directives"),)
+        }
+
+        #[test]
+        fn joins_hook_lines() {
+            let directives = Directives::from_directives(HashMap::from([(
+                "postInstall",
+                vec!["mkdir -p $out/share/man", "cp x.1 $out/share/man"],
+            )]))
+            .unwrap();
+
+            assert_eq!(
+                Some("mkdir -p $out/share/man\ncp x.1 $out/share/man".to_string()),
+                directives.post_install
+            );
+        }
+
+        #[test]
+        fn combines_binaries() {
+            let directives =
+                Directives::from_directives(HashMap::from([("binaries", vec!["a b", "b c"])]))
+                    .unwrap();
+
+            assert_eq!(vec!["a", "b", "c"], directives.binaries);
+        }
+
+        #[test]
+        fn allow_network_without_value() {
+            let directives =
+                Directives::from_directives(HashMap::from([("allowNetwork", vec![""])])).unwrap();
+
+            assert!(directives.allow_network);
+        }
+
+        #[test]
+        fn allow_network_can_be_turned_off() {
+            let directives =
+                Directives::from_directives(HashMap::from([("allowNetwork", vec!["", "false"])]))
+                    .unwrap();
+
+            assert!(!directives.allow_network);
+        }
+
+        #[test]
+        fn allow_network_rejects_other_values() {
+            let problem =
+                Directives::from_directives(HashMap::from([("allowNetwork", vec!["yes"])]))
+                    .unwrap_err();
+
+            assert_eq!(
+                "`allowNetwork` directive should have no value, `true` or `false`, but was `yes`",
+                problem.to_string()
+            );
+        }
+
+        #[test]
+        fn combines_allow_env() {
+            let directives = Directives::from_directives(HashMap::from([(
+                "allowEnv",
+                vec!["HOME TERM", "TERM"],
+            )]))
+            .unwrap();
+
+            assert_eq!(vec!["HOME", "TERM"], directives.allow_env);
+        }
+
+        #[test]
+        fn allow_env_must_be_names() {
+            let problem = Directives::from_directives(HashMap::from([("allowEnv", vec!["A=b"])]))
+                .unwrap_err();
+
+            assert_eq!(
+                "`A=b` is not a valid environment variable name",
+                problem.to_string()
+            );
+        }
+
+        #[test]
+        fn binaries_must_be_names() {
+            let problem =
+                Directives::from_directives(HashMap::from([("binaries", vec!["../evil"])]))
+                    .unwrap_err();
+
+            assert_eq!("`../evil` is not a valid binary name", problem.to_string());
+        }
+
+        #[test]
+        fn combines_wrapper_args() {
+            let directives = Directives::from_directives(HashMap::from([(
+                "wrapperArgs",
+                vec!["--chdir /tmp", "--unset DEBUG --add-flags -v"],
+            )]))
+            .unwrap();
+
+            assert_eq!(3, directives.wrapper_args.len());
+        }
+
+        #[test]
+        fn wrapper_args_must_be_valid() {
+            let problem =
+                Directives::from_directives(HashMap::from([("wrapperArgs", vec!["--chdir"])]))
+                    .unwrap_err();
+
+            assert!(problem
+                .to_string()
+                .contains("could not parse `wrapperArgs` directive"))
+        }
+
         #[test]
         fn combines_build_inputs() {
             let directives =
@@ -301,6 +1105,59 @@ mod tests {
             assert_eq!(expected, directives.runtime_files);
         }
 
+        #[test]
+        fn runtime_files_ignore_extra_spaces() {
+            let directives =
+                Directives::from_directives(HashMap::from([("runtimeFiles", vec!["a  b "])]))
+                    .unwrap();
+
+            assert_eq!(
+                vec![PathBuf::from("a"), PathBuf::from("b")],
+                directives.runtime_files
+            );
+        }
+
+        #[test]
+        fn runtime_files_can_be_quoted() {
+            let directives = Directives::from_directives(HashMap::from([(
+                "runtimeFiles",
+                vec!["'a b' \"c d\" e\\ f"],
+            )]))
+            .unwrap();
+
+            assert_eq!(
+                vec![
+                    PathBuf::from("a b"),
+                    PathBuf::from("c d"),
+                    PathBuf::from("e f")
+                ],
+                directives.runtime_files
+            );
+        }
+
+        #[test]
+        fn combines_build_files() {
+            let directives =
+                Directives::from_directives(HashMap::from([("buildFiles", vec!["a b", "'c d'"])]))
+                    .unwrap();
+
+            assert_eq!(
+                vec![PathBuf::from("a"), PathBuf::from("b"), PathBuf::from("c d")],
+                directives.build_files
+            );
+        }
+
+        #[test]
+        fn runtime_files_must_close_quotes() {
+            let problem =
+                Directives::from_directives(HashMap::from([("runtimeFiles", vec!["'a b"])]))
+                    .unwrap_err();
+
+            assert!(problem
+                .to_string()
+                .contains("could not split `runtimeFiles`"))
+        }
+
         #[test]
         fn includes_others_raw() {
             let directives =
@@ -344,6 +1201,37 @@ mod tests {
                 directives.nixpkgs_config.map(|o| o.to_string()),
             )
         }
+
+        #[test]
+        fn combines_env() {
+            let directives =
+                Directives::from_directives(HashMap::from([("env", vec!["A=1 B=2", "C=3"])]))
+                    .unwrap();
+
+            let names: Vec<&str> = directives.env.iter().map(|var| var.name()).collect();
+            assert_eq!(vec!["A", "B", "C"], names);
+        }
+
+        #[test]
+        fn env_must_be_valid() {
+            let problem =
+                Directives::from_directives(HashMap::from([("env", vec!["nope"])])).unwrap_err();
+
+            assert!(problem
+                .to_string()
+                .contains("could not parse `env` directive"))
+        }
+
+        #[test]
+        fn combines_build_env() {
+            let directives = Directives::from_directives(HashMap::from([(
+                "buildEnv",
+                vec!["A=1", "--prefix PATH : /opt/bin"],
+            )]))
+            .unwrap();
+
+            assert_eq!(2, directives.build_env.len());
+        }
     }
 
     mod hash {
@@ -363,6 +1251,81 @@ mod tests {
             assert!(l_hasher.finish() != r_hasher.finish())
         }
 
+        #[test]
+        fn check_command_changes_hash() {
+            assert_have_different_hashes(
+                Directives::from_directives(HashMap::from([("build", vec!["a"])])).unwrap(),
+                Directives::from_directives(HashMap::from([
+                    ("build", vec!["a"]),
+                    ("check", vec!["$OUT --self-test"]),
+                ]))
+                .unwrap(),
+            )
+        }
+
+        #[test]
+        fn env_and_build_env_hash_differently() {
+            assert_have_different_hashes(
+                Directives::from_directives(HashMap::from([("env", vec!["A=1"])])).unwrap(),
+                Directives::from_directives(HashMap::from([("buildEnv", vec!["A=1"])])).unwrap(),
+            )
+        }
+
+        #[test]
+        fn build_and_runtime_inputs_hash_differently() {
+            assert_have_different_hashes(
+                Directives::from_directives(HashMap::from([("buildInputs", vec!["jq"])])).unwrap(),
+                Directives::from_directives(HashMap::from([("runtimeInputs", vec!["jq"])]))
+                    .unwrap(),
+            )
+        }
+
+        #[test]
+        fn runtime_and_build_files_hash_differently() {
+            assert_have_different_hashes(
+                Directives::from_directives(HashMap::from([("runtimeFiles", vec!["x"])])).unwrap(),
+                Directives::from_directives(HashMap::from([("buildFiles", vec!["x"])])).unwrap(),
+            )
+        }
+
+        #[test]
+        fn build_and_check_hash_differently_from_concatenation() {
+            assert_have_different_hashes(
+                Directives::from_directives(HashMap::from([
+                    ("build", vec!["x"]),
+                    ("check", vec!["y"]),
+                ]))
+                .unwrap(),
+                Directives::from_directives(HashMap::from([("build", vec!["xy"])])).unwrap(),
+            )
+        }
+
+        #[test]
+        fn hooks_change_hash() {
+            assert_have_different_hashes(
+                Directives::from_directives(HashMap::from([("preBuild", vec!["a"])])).unwrap(),
+                Directives::from_directives(HashMap::from([("preBuild", vec!["b"])])).unwrap(),
+            )
+        }
+
+        #[test]
+        fn hook_name_changes_hash() {
+            assert_have_different_hashes(
+                Directives::from_directives(HashMap::from([("preBuild", vec!["a"])])).unwrap(),
+                Directives::from_directives(HashMap::from([("postBuild", vec!["a"])])).unwrap(),
+            )
+        }
+
+        #[test]
+        fn install_command_changes_hash() {
+            assert_have_different_hashes(
+                Directives::from_directives(HashMap::from([("installCommand", vec!["a"])]))
+                    .unwrap(),
+                Directives::from_directives(HashMap::from([("installCommand", vec!["b"])]))
+                    .unwrap(),
+            )
+        }
+
         #[test]
         fn build_command_changes_hash() {
             assert_have_different_hashes(
@@ -411,6 +1374,48 @@ mod tests {
             )
         }
 
+        #[test]
+        fn build_files_change_hash() {
+            assert_have_different_hashes(
+                Directives::from_directives(HashMap::from([("buildFiles", vec!["a"])])).unwrap(),
+                Directives::from_directives(HashMap::from([("buildFiles", vec!["b"])])).unwrap(),
+            )
+        }
+
+        #[test]
+        fn binaries_change_hash() {
+            assert_have_different_hashes(
+                Directives::from_directives(HashMap::from([("binaries", vec!["a"])])).unwrap(),
+                Directives::from_directives(HashMap::from([("binaries", vec!["b"])])).unwrap(),
+            )
+        }
+
+        #[test]
+        fn wrapper_args_change_hash() {
+            assert_have_different_hashes(
+                Directives::from_directives(HashMap::from([("wrapperArgs", vec!["--chdir a"])]))
+                    .unwrap(),
+                Directives::from_directives(HashMap::from([("wrapperArgs", vec!["--chdir b"])]))
+                    .unwrap(),
+            )
+        }
+
+        #[test]
+        fn env_changes_hash() {
+            assert_have_different_hashes(
+                Directives::from_directives(HashMap::from([("env", vec!["A=1"])])).unwrap(),
+                Directives::from_directives(HashMap::from([("env", vec!["A=2"])])).unwrap(),
+            )
+        }
+
+        #[test]
+        fn build_env_changes_hash() {
+            assert_have_different_hashes(
+                Directives::from_directives(HashMap::from([("buildEnv", vec!["A=1"])])).unwrap(),
+                Directives::from_directives(HashMap::from([("buildEnv", vec!["A=2"])])).unwrap(),
+            )
+        }
+
         #[test]
         fn nixpkgs_config_changes_hash() {
             assert_have_different_hashes(
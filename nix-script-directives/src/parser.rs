@@ -1,37 +1,52 @@
 use anyhow::Result;
 use std::collections::HashMap;
 
+/// Directives that switch something on just by being there, so they don't
+/// need a value.
+const FLAGS: &[&str] = &["allowNetwork"];
+
 #[derive(Debug)]
 pub struct Parser {
-    indicator: String,
+    // Sorted longest first, so that `#!` wins over `#` if we have both.
+    indicators: Vec<String>,
 }
 
 impl Parser {
-    pub fn new(indicator: &str) -> Result<Self> {
-        if indicator.is_empty() {
+    pub fn new(indicators: &[&str]) -> Result<Self> {
+        if indicators.is_empty() {
+            anyhow::bail!("need at least one indicator")
+        }
+
+        if indicators.iter().any(|indicator| indicator.is_empty()) {
             anyhow::bail!("a blank indicator is not allowed")
         }
 
-        Ok(Parser {
-            indicator: indicator.to_string(),
-        })
+        let mut indicators: Vec<String> = indicators.iter().map(|i| i.to_string()).collect();
+        indicators.sort_by_key(|indicator| std::cmp::Reverse(indicator.len()));
+
+        Ok(Parser { indicators })
     }
 
     pub fn parse<'a>(&self, source: &'a str) -> HashMap<&'a str, Vec<&'a str>> {
         let mut out = HashMap::new();
 
         for line in source.lines() {
-            if !line.starts_with(&self.indicator) {
-                continue;
-            }
-
-            let line_without_indicator = line[self.indicator.len()..].trim_start();
+            let indicator = match self
+                .indicators
+                .iter()
+                .find(|indicator| line.starts_with(indicator.as_str()))
+            {
+                Some(indicator) => indicator,
+                None => continue,
+            };
+
+            let line_without_indicator = line[indicator.len()..].trim_start();
             let mut words = line_without_indicator.split_whitespace();
 
             if let Some(key) = words.next() {
                 let value = line_without_indicator[key.len()..].trim_start();
 
-                if value.is_empty() {
+                if value.is_empty() && !FLAGS.contains(&key) {
                     log::warn!("skipping directive \"{key}\" because value was empty");
                     continue;
                 }
@@ -52,11 +67,19 @@ mod tests {
     mod new {
         use super::*;
 
+        #[test]
+        fn needs_an_indicator() {
+            assert_eq!(
+                "need at least one indicator",
+                Parser::new(&[]).unwrap_err().to_string(),
+            )
+        }
+
         #[test]
         fn blank_indicator_is_not_allowed() {
             assert_eq!(
                 "a blank indicator is not allowed",
-                Parser::new("").unwrap_err().to_string(),
+                Parser::new(&[""]).unwrap_err().to_string(),
             )
         }
     }
@@ -66,42 +89,65 @@ mod tests {
 
         #[test]
         fn blank_is_blank() {
-            let directives = Parser::new("#!").unwrap().parse("");
+            let directives = Parser::new(&["#!"]).unwrap().parse("");
 
             assert!(directives.is_empty());
         }
 
         #[test]
         fn ignores_non_shebangs() {
-            let directives = Parser::new("#!").unwrap().parse("nope");
+            let directives = Parser::new(&["#!"]).unwrap().parse("nope");
 
             assert!(directives.is_empty());
         }
 
         #[test]
         fn matches_shebangs() {
-            let directives = Parser::new("#!").unwrap().parse("#!buildInputs jq");
+            let directives = Parser::new(&["#!"]).unwrap().parse("#!buildInputs jq");
 
             assert_eq!(Some(&vec!["jq"]), directives.get("buildInputs"));
         }
 
         #[test]
         fn matches_comment_chars() {
-            let directives = Parser::new("//").unwrap().parse("// buildInputs jq");
+            let directives = Parser::new(&["//"]).unwrap().parse("// buildInputs jq");
 
             assert_eq!(Some(&vec!["jq"]), directives.get("buildInputs"));
         }
 
+        #[test]
+        fn matches_any_indicator() {
+            let directives = Parser::new(&["#!", "--"])
+                .unwrap()
+                .parse("#!buildInputs a\n-- buildInputs b");
+
+            assert_eq!(Some(&vec!["a", "b"]), directives.get("buildInputs"));
+        }
+
+        #[test]
+        fn prefers_longest_indicator() {
+            let directives = Parser::new(&["#", "#!"]).unwrap().parse("#!buildInputs a");
+
+            assert_eq!(Some(&vec!["a"]), directives.get("buildInputs"));
+        }
+
         #[test]
         fn removes_empty_directives() {
-            let directives = Parser::new("#!").unwrap().parse("#!buildInputs");
+            let directives = Parser::new(&["#!"]).unwrap().parse("#!buildInputs");
 
             assert_eq!(None, directives.get("buildInputs"));
         }
 
+        #[test]
+        fn keeps_empty_flags() {
+            let directives = Parser::new(&["#!"]).unwrap().parse("#!allowNetwork");
+
+            assert_eq!(Some(&vec![""]), directives.get("allowNetwork"));
+        }
+
         #[test]
         fn combines_multiple_lines() {
-            let directives = Parser::new("#!")
+            let directives = Parser::new(&["#!"])
                 .unwrap()
                 .parse("#!buildInputs a\n#!buildInputs b");
 
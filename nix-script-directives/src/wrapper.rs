@@ -0,0 +1,129 @@
+use anyhow::{Context, Result};
+
+/// The `makeWrapper` flags we accept, and how many arguments each takes.
+const FLAGS: &[(&str, usize)] = &[
+    ("--argv0", 1),
+    ("--inherit-argv0", 0),
+    ("--set", 2),
+    ("--set-default", 2),
+    ("--unset", 1),
+    ("--chdir", 1),
+    ("--run", 1),
+    ("--prefix", 3),
+    ("--suffix", 3),
+    ("--prefix-each", 3),
+    ("--suffix-each", 3),
+    ("--prefix-contents", 3),
+    ("--suffix-contents", 3),
+    ("--add-flags", 1),
+    ("--append-flags", 1),
+    ("--add-flag", 1),
+    ("--append-flag", 1),
+];
+
+/// A flag passed through to `makeWrapper` by `#!wrapperArgs`, like
+/// `--chdir /tmp` or `--add-flags "--verbose"`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize)]
+pub struct WrapperArg {
+    pub flag: String,
+    pub args: Vec<String>,
+}
+
+impl WrapperArg {
+    /// Parse a directive line, which is split like shell words and may
+    /// contain several flags.
+    pub fn parse_line(line: &str) -> Result<Vec<Self>> {
+        let words = shell_words::split(line).context("could not split wrapper arguments")?;
+        let mut words = words.into_iter();
+        let mut out = Vec::new();
+
+        while let Some(flag) = words.next() {
+            let arity = match FLAGS.iter().find(|(known, _)| *known == flag) {
+                Some((_, arity)) => *arity,
+                None => anyhow::bail!("`{}` is not a makeWrapper flag I know about", flag),
+            };
+
+            let args: Vec<String> = words.by_ref().take(arity).collect();
+            if args.len() != arity {
+                anyhow::bail!(
+                    "`{}` needs {} argument(s), but got {}",
+                    flag,
+                    arity,
+                    args.len()
+                )
+            }
+
+            out.push(WrapperArg { flag, args });
+        }
+
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod parse_line {
+        use super::*;
+
+        #[test]
+        fn flag_without_arguments() {
+            assert_eq!(
+                vec![WrapperArg {
+                    flag: "--inherit-argv0".into(),
+                    args: Vec::new()
+                }],
+                WrapperArg::parse_line("--inherit-argv0").unwrap()
+            )
+        }
+
+        #[test]
+        fn flags_with_arguments() {
+            assert_eq!(
+                vec![
+                    WrapperArg {
+                        flag: "--chdir".into(),
+                        args: vec!["/tmp".into()]
+                    },
+                    WrapperArg {
+                        flag: "--prefix".into(),
+                        args: vec!["PYTHONPATH".into(), ":".into(), "./lib".into()]
+                    },
+                ],
+                WrapperArg::parse_line("--chdir /tmp --prefix PYTHONPATH : ./lib").unwrap()
+            )
+        }
+
+        #[test]
+        fn quoted_arguments() {
+            assert_eq!(
+                vec![WrapperArg {
+                    flag: "--add-flags".into(),
+                    args: vec!["--verbose --color=always".into()]
+                }],
+                WrapperArg::parse_line("--add-flags '--verbose --color=always'").unwrap()
+            )
+        }
+
+        #[test]
+        fn rejects_unknown_flags() {
+            let problem = WrapperArg::parse_line("--frobnicate").unwrap_err();
+
+            assert_eq!(
+                "`--frobnicate` is not a makeWrapper flag I know about",
+                problem.to_string()
+            )
+        }
+
+        #[test]
+        fn rejects_missing_arguments() {
+            let problem = WrapperArg::parse_line("--set A").unwrap_err();
+
+            assert_eq!(
+                "`--set` needs 2 argument(s), but got 1",
+                problem.to_string()
+            )
+        }
+    }
+}
@@ -2,6 +2,7 @@ mod opts;
 
 use clap::Parser;
 use opts::Opts;
+use std::os::unix::process::ExitStatusExt;
 
 fn main() {
     env_logger::Builder::from_env("NIX_SCRIPT_LOG").init();
@@ -9,10 +10,15 @@ fn main() {
     let opts = Opts::parse();
     log::trace!("opts: {opts:?}");
 
-    match opts.run().map(|status| status.code()) {
+    // Like a shell, we report a child killed by a signal as 128 plus the
+    // signal number.
+    match opts
+        .run()
+        .map(|status| status.code().or(status.signal().map(|signal| 128 + signal)))
+    {
         Ok(Some(code)) => std::process::exit(code),
         Ok(None) => {
-            log::warn!("no exit code; was the script killed with a signal?");
+            log::warn!("no exit code or signal; was the script stopped?");
             std::process::exit(1)
         }
         Err(err) => {
@@ -1,6 +1,7 @@
 use anyhow::{Context, Result};
 use clap::Parser;
 use nix_script_directives::Directives;
+use std::os::unix::process::CommandExt;
 use std::path::PathBuf;
 use std::process::{Command, ExitStatus};
 
@@ -10,7 +11,9 @@ use std::process::{Command, ExitStatus};
 /// I pay attention to all the same #! directives as nix-script, so you can
 /// still use `#!runtimeInputs` and friends to get external dependencies. (There
 /// is no need to specify `#!build` or `#!buildInputs` with regards to GHC or
-/// packages, though; I take care of that.)
+/// packages, though; I take care of that.) Since GHC does not like `#!` lines
+/// after the first, you can also write directives as comments, like
+/// `-- nix-script: runtimeInputs jq`.
 ///
 /// In addition, I pay attention to some additional directives specific to
 /// Haskell programs:
@@ -56,7 +59,7 @@ impl Opts {
             .get_script_and_args()
             .context("could not get script and args")?;
 
-        let directives = Directives::from_file("#!", &script)
+        let directives = Directives::from_file(None, &script)
             .context("could not parse directives from script")?;
 
         let mut nix_script = Command::new(&self.nix_script_bin);
@@ -99,14 +102,16 @@ impl Opts {
         nix_script.arg(script);
         nix_script.args(args);
 
-        let mut child = nix_script.spawn().with_context(|| {
+        // Hand over to nix-script, so signals and our PID go straight to it.
+        // `exec` only returns if it failed.
+        let err = nix_script.exec();
+
+        Err(err).with_context(|| {
             format!(
                 "could not call {}. Is it on the PATH?",
                 self.nix_script_bin.display()
             )
-        })?;
-
-        child.wait().context("could not run the script")
+        })
     }
 
     fn get_script_and_args(&self) -> Result<(PathBuf, Vec<String>)> {
@@ -1,22 +1,37 @@
 use crate::clean_path::clean_path;
 use crate::derivation::Derivation;
+use crate::index::{FileStat, Index};
+use crate::rewrite::PathRewriter;
 use anyhow::{Context, Result};
 use nix_script_directives::Directives;
 use once_cell::unsync::OnceCell;
 use path_absolutize::Absolutize;
+use regex::Regex;
 use seahash::SeaHasher;
-use std::fs;
+use std::fs::{self, File};
 use std::hash::Hash;
 use std::hash::Hasher;
-use std::io::ErrorKind;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::thread::{self, JoinHandle};
 use walkdir::WalkDir;
 
 #[derive(Debug)]
 pub struct Builder {
     source: Source,
+
+    // Blank out lines starting with these indicators before building.
+    strip_indicators: Vec<String>,
+
+    // Copy the whole build root into the store instead of only the files
+    // the script says it needs.
+    whole_root: bool,
+
+    // Leave the files we and Nix build from in place, for debugging.
+    keep_build_dir: bool,
+    print_derivation: bool,
 }
 
 lazy_static::lazy_static! {
@@ -32,6 +47,10 @@ impl Builder {
                 script: script.to_owned(),
                 tempdir: OnceCell::new(),
             },
+            strip_indicators: Vec::new(),
+            whole_root: false,
+            keep_build_dir: false,
+            print_derivation: false,
         }
     }
 
@@ -59,9 +78,93 @@ impl Builder {
                 root,
                 tempdir: OnceCell::new(),
             },
+            strip_indicators: Vec::new(),
+            whole_root: false,
+            keep_build_dir: false,
+            print_derivation: false,
         })
     }
 
+    pub fn include_whole_root(&mut self) {
+        self.whole_root = true;
+    }
+
+    /// Keep our temporary build directory (and Nix's build tree, if the build
+    /// fails) and say where they are.
+    pub fn keep_build_dir(&mut self) {
+        self.keep_build_dir = true;
+    }
+
+    /// Print the derivation to stderr when we build it.
+    pub fn print_derivation(&mut self) {
+        self.print_derivation = true;
+    }
+
+    /// A builder for the same script and options, but with the script (and
+    /// everything else it needs) in another root directory.
+    pub fn relocated(&self, root: &Path) -> Result<Self> {
+        let script = self
+            .source
+            .script()
+            .context("could not get the path to the script")?;
+
+        let root = clean_path(root).context("could not clean path to new root")?;
+        let mut out = Self::from_directory(&root, &root.join(script))?;
+        out.strip_indicators = self.strip_indicators.clone();
+        out.whole_root = self.whole_root;
+        out.keep_build_dir = self.keep_build_dir;
+        out.print_derivation = self.print_derivation;
+
+        Ok(out)
+    }
+
+    /// The files the build needs, relative to [`Builder::files_root`]: the
+    /// script, its runtime files and its build files. When building with the
+    /// whole root, this is still only what the script declares.
+    pub fn source_files(&self, directives: &Directives) -> Result<Vec<PathBuf>> {
+        let root = self.files_root()?;
+
+        let mut files = vec![self
+            .source
+            .script()
+            .context("could not get the path to the script")?
+            .to_owned()];
+        files.extend(
+            runtime_files(root, &directives.runtime_files)
+                .context("could not expand runtime files")?
+                .into_iter()
+                .map(|(src, _)| src),
+        );
+        files.extend(
+            expand_globs(root, &directives.build_files).context("could not expand build files")?,
+        );
+        files.dedup();
+
+        Ok(files)
+    }
+
+    /// Where the files named in directives live on disk.
+    pub fn files_root(&self) -> Result<&Path> {
+        self.source.files_root()
+    }
+
+    pub fn has_default_nix(&self) -> bool {
+        self.source.has_default_nix()
+    }
+
+    /// Whether the script is built from a build root instead of on its own.
+    pub fn has_build_root(&self) -> bool {
+        matches!(self.source, Source::Directory { .. })
+    }
+
+    pub fn includes_whole_root(&self) -> bool {
+        self.whole_root
+    }
+
+    pub fn strip_directives(&mut self, indicators: &[String]) {
+        self.strip_indicators = indicators.to_vec();
+    }
+
     pub fn derivation(&self, directives: &Directives, for_export: bool) -> Result<Derivation> {
         let build_command = match &directives.build_command {
             Some(bc) => bc,
@@ -86,6 +189,35 @@ impl Builder {
         )
         .context("could not create a Nix derivation")?;
 
+        if let Some(check_command) = &directives.check_command {
+            log::trace!("adding check command");
+            derivation.set_check_command(check_command);
+        }
+
+        if let Some(install_command) = &directives.install_command {
+            log::trace!("adding install command");
+            derivation.set_install_command(install_command);
+        }
+
+        for (name, script) in [
+            ("preBuild", &directives.pre_build),
+            ("postBuild", &directives.post_build),
+            ("preInstall", &directives.pre_install),
+            ("postInstall", &directives.post_install),
+        ] {
+            if let Some(script) = script {
+                log::trace!("adding {name} hook");
+                derivation.set_hook(name, script);
+            }
+        }
+
+        if let (true, Source::Script { script, .. }) = (for_export, &self.source) {
+            log::trace!("embedding script for export");
+            derivation.embed_script(
+                &fs::read_to_string(script).context("could not read script to embed it")?,
+            );
+        }
+
         log::trace!("adding build inputs");
         derivation.add_build_inputs(directives.build_inputs.clone());
 
@@ -93,7 +225,32 @@ impl Builder {
         derivation.add_runtime_inputs(directives.runtime_inputs.clone());
 
         log::trace!("adding runtime files");
-        derivation.add_runtime_files(directives.runtime_files.clone());
+        let runtime_files = runtime_files(self.source.files_root()?, &directives.runtime_files)
+            .context("could not expand runtime files")?;
+
+        // Scripts without a build root get the same treatment so that the
+        // name of the temporary directory doesn't end up in the source.
+        if !self.whole_root {
+            log::trace!("limiting source to the files we need");
+            derivation.only_include(self.source_files(directives)?);
+        }
+
+        derivation
+            .add_runtime_files(runtime_files)
+            .context("could not add runtime files")?;
+
+        log::trace!("adding binaries");
+        derivation.add_binaries(directives.binaries.clone());
+
+        log::trace!("adding environment variables");
+        derivation.add_env(directives.env.clone());
+        derivation.add_build_env(directives.build_env.clone());
+        derivation.add_wrapper_args(directives.wrapper_args.clone());
+
+        if !self.strip_indicators.is_empty() {
+            log::trace!("stripping directive lines");
+            derivation.set_strip_indicators(self.strip_indicators.clone());
+        }
 
         if let Some(interpreter) = &directives.interpreter {
             log::debug!("using interpreter from directives");
@@ -108,6 +265,65 @@ impl Builder {
     }
 
     pub fn hash(&self, directives: &Directives) -> Result<String> {
+        let mut hasher = self.settings_hasher(directives);
+
+        self.source
+            .hash(&mut hasher)
+            .context("could not hash source")?;
+        log::trace!("hashed source, hash is now {:x}", hasher.finish());
+
+        for include in &directives.includes {
+            hasher.write(include.as_os_str().as_bytes());
+            hasher.write(
+                &fs::read(include)
+                    .with_context(|| format!("could not read {}", include.display()))?,
+            );
+        }
+        log::trace!("hashed included files, hash is now {:x}", hasher.finish());
+
+        Ok(format!("{:x}", hasher.finish()))
+    }
+
+    /// The same as [`Builder::hash`], but if the source and included files
+    /// have the same size, mtime and inode as the last time we hashed them,
+    /// reuse that hash instead of reading them all again.
+    pub fn indexed_hash(&self, directives: &Directives, index: &Index) -> Result<String> {
+        let script = self
+            .files_root()?
+            .join(self.source.script()?)
+            .absolutize()
+            .context("could not find the absolute path to the script")?
+            .into_owned();
+
+        let mut settings = self.settings_hasher(directives);
+        let mut files = self.source.files().context("could not list source files")?;
+        files.extend(directives.includes.iter().cloned());
+        for file in &files {
+            settings.write(file.as_os_str().as_bytes());
+        }
+        let settings = format!("{:x}", settings.finish());
+
+        let stats = files
+            .iter()
+            .map(|file| FileStat::new(file))
+            .collect::<Result<Vec<_>>>()
+            .context("could not stat source files")?;
+
+        if let Some(hash) = index.lookup(&script, &settings, &stats) {
+            log::debug!("source files are unchanged; reusing hash {hash}");
+            return Ok(hash);
+        }
+
+        let hash = self.hash(directives)?;
+        if let Err(err) = index.store(&script, &settings, stats, &hash) {
+            log::warn!("could not update the hash index: {err:?}");
+        }
+
+        Ok(hash)
+    }
+
+    /// A hasher with everything but the contents of files in it.
+    fn settings_hasher(&self, directives: &Directives) -> SeaHasher {
         let mut hasher = SeaHasher::new();
 
         // TODO: should we use the derivation here instead? It seems like this
@@ -117,6 +333,11 @@ impl Builder {
         directives.hash(&mut hasher);
         log::trace!("hashed directives, hash is now {:x}", hasher.finish());
 
+        for indicator in &self.strip_indicators {
+            hasher.write(indicator.as_ref());
+        }
+        hasher.write_u8(self.whole_root.into());
+
         let out = std::env::var_os("NIX_PATH");
         match out {
             Some(nix_path) => {
@@ -126,19 +347,96 @@ impl Builder {
             None => log::warn!("no NIX_PATH environment variable; updates to <nixpkgs> may not trigger rebuilds of scripts"),
         };
 
-        self.source
-            .hash(&mut hasher)
-            .context("could not hash source")?;
-        log::trace!("hashed source, hash is now {:x}", hasher.finish());
+        hasher
+    }
 
-        Ok(format!("{:x}", hasher.finish()))
+    /// The directory the derivation's `src` comes from: our copy of a lone
+    /// script, or the (possibly relocated) build root.
+    fn built_source(&self) -> Result<PathBuf> {
+        absolute(
+            self.source
+                .root()
+                .context("could not get the root directory of the derivation")?,
+        )
+    }
+
+    /// Where we keep the log of the build with this hash.
+    pub fn log_path(&self, cache_root: &Path, hash: &str) -> Result<PathBuf> {
+        let script_name = self
+            .source
+            .script()?
+            .file_name()
+            .context("the script path did not have a file name")?
+            .to_str()
+            .context("filename was not valid UTF-8")?;
+
+        Ok(cache_root
+            .join(".logs")
+            .join(format!("{hash}-{script_name}.log")))
     }
 
+    /// The log for the build with this hash or, if there isn't one, the
+    /// newest log for any version of the script. Editing a script after a
+    /// failed build changes the hash, but that failure is still the log
+    /// people want to see.
+    pub fn latest_log_path(&self, cache_root: &Path, hash: &str) -> Result<Option<PathBuf>> {
+        let current = self.log_path(cache_root, hash)?;
+        if current.exists() {
+            return Ok(Some(current));
+        }
+
+        let suffix = format!(
+            "-{}",
+            current
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix(hash))
+                .and_then(|name| name.strip_prefix('-'))
+                .context("could not get the name of the log file")?
+        );
+
+        let entries = match fs::read_dir(cache_root.join(".logs")) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context("could not read the build logs"),
+        };
+
+        let mut latest = None;
+        for entry in entries {
+            let entry = entry.context("could not read the build logs")?;
+            let name = entry.file_name();
+            // Only `<hash>-<script name>.log`, so `a-b.log` isn't taken for
+            // a log of `b`.
+            let is_ours = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(&suffix))
+                .is_some_and(|hash| {
+                    !hash.is_empty() && hash.chars().all(|c| c.is_ascii_hexdigit())
+                });
+            if !is_ours {
+                continue;
+            }
+
+            let modified = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .context("could not check when a build log was written")?;
+            if latest.as_ref().is_none_or(|(newest, _)| modified > *newest) {
+                latest = Some((modified, entry.path()));
+            }
+        }
+
+        Ok(latest.map(|(_, path)| path))
+    }
+
+    /// Build the script, saving nix-build's output in the log. With `quiet`,
+    /// the output is only shown if the build fails.
     pub fn build(
         &mut self,
         cache_root: &Path,
         hash: &str,
         directives: &Directives,
+        quiet: bool,
     ) -> Result<PathBuf> {
         self.source
             .isolate(cache_root, hash)
@@ -148,7 +446,8 @@ impl Builder {
         let build_path = self
             .source
             .derivation_path(cache_root, hash)
-            .context("could not determine where to run the build")?;
+            .context("could not determine where to run the build")?
+            .to_owned();
         log::trace!("run the build in {}", build_path.display());
 
         if !self.source.has_default_nix() {
@@ -162,15 +461,47 @@ impl Builder {
                 .context("could not write derivation contents")?;
         }
 
-        log::info!("building");
-        let mut output = Command::new("nix-build")
-            .arg(build_path)
+        let default_nix = build_path.join("default.nix");
+        if self.print_derivation {
+            let derivation =
+                fs::read_to_string(&default_nix).context("could not read the derivation")?;
+            eprintln!("{}", derivation.trim_end());
+        }
+
+        // Errors should point at the user's files, not our copies of them.
+        let mut build_roots = vec![absolute(&build_path)?];
+        if let Ok(root) = self.source.root() {
+            build_roots.push(absolute(root)?);
+        }
+        let rewriter = PathRewriter::new(
+            &absolute(self.source.files_root()?)?,
+            &build_roots,
+            &self.source_files(directives)?,
+        )
+        .context("could not prepare to rewrite paths in the build output")?;
+
+        let log_path = self.log_path(cache_root, hash)?;
+        fs::create_dir_all(log_path.parent().context("log path has no parent")?)
+            .context("could not create directory for build logs")?;
+        let log = File::create(&log_path).context("could not create build log")?;
+        log::debug!("logging the build to {}", log_path.display());
+
+        let mut command = Command::new("nix-build");
+        command
+            .arg(&build_path)
             // TODO: It might be good to explicitly set `--out-link` to
             // somewhere in the cache!
-            .arg("--no-out-link")
+            .arg("--no-out-link");
+        if self.keep_build_dir {
+            self.source.keep_tempdir();
+            command.arg("--keep-failed");
+        }
+
+        log::info!("building");
+        let mut child = command
             .stdout(Stdio::piped())
-            .stderr(Stdio::inherit())
-            .output()
+            .stderr(Stdio::piped())
+            .spawn()
             .map_err(|err| match err.kind() {
                 ErrorKind::NotFound => {
                     anyhow::anyhow!("No nix-build binary available. Is Nix installed?")
@@ -179,9 +510,45 @@ impl Builder {
             })
             .context("failed to build")?;
 
+        let tee = tee_to_log(
+            child
+                .stderr
+                .take()
+                .context("nix-build has no stderr; this is a bug; please report")?,
+            log,
+            !quiet,
+            rewriter,
+        );
+        let mut output = child
+            .wait_with_output()
+            .context("could not wait for nix-build")?;
+        match tee.join() {
+            Ok(result) => result.context("could not write build log")?,
+            Err(_) => anyhow::bail!("the thread writing the build log panicked"),
+        }
+
+        if !output.status.success() && quiet {
+            let mut stderr = io::stderr();
+            stderr
+                .write_all(&fs::read(&log_path).context("could not read build log")?)
+                .context("could not show build log")?;
+        }
+
+        if self.keep_build_dir {
+            eprintln!("derivation: {}", default_nix.display());
+            eprintln!("source: {}", self.built_source()?.display());
+            if let Some(kept) = kept_build_tree(&log_path)? {
+                eprintln!("failed build: {kept}");
+            }
+        }
+
         match output.status.code() {
             Some(0) => {}
-            Some(other) => anyhow::bail!("nix-build exited with code {}", other),
+            Some(other) => anyhow::bail!(
+                "nix-build exited with code {} (the log is in {})",
+                other,
+                log_path.display()
+            ),
             None => anyhow::bail!("nix-build was terminated by a signal"),
         }
 
@@ -203,6 +570,56 @@ impl Builder {
     }
 }
 
+/// Where Nix left the build tree of a failed build, going by the log.
+fn kept_build_tree(log_path: &Path) -> Result<Option<String>> {
+    lazy_static::lazy_static! {
+        static ref KEEPING: Regex = Regex::new("keeping build directory '([^']+)'").unwrap();
+    }
+
+    let log = fs::read_to_string(log_path).context("could not read build log")?;
+
+    Ok(KEEPING
+        .captures_iter(&log)
+        .last()
+        .map(|captures| captures[1].to_owned()))
+}
+
+fn absolute(path: &Path) -> Result<PathBuf> {
+    Ok(path
+        .absolutize()
+        .with_context(|| format!("could not make {} absolute", path.display()))?
+        .into_owned())
+}
+
+/// Copy everything from `input` to `log` in the background, and to stderr
+/// as well if `echo` is set. Paths are rewritten line by line on the way.
+fn tee_to_log<R: Read + Send + 'static>(
+    input: R,
+    mut log: File,
+    echo: bool,
+    rewriter: PathRewriter,
+) -> JoinHandle<io::Result<()>> {
+    thread::spawn(move || {
+        let mut input = BufReader::new(input);
+        let mut line = Vec::new();
+
+        loop {
+            line.clear();
+            if input.read_until(b'\n', &mut line)? == 0 {
+                return log.flush();
+            }
+
+            let rewritten = rewriter
+                .rewrite(&String::from_utf8_lossy(&line))
+                .into_owned();
+            log.write_all(rewritten.as_bytes())?;
+            if echo {
+                io::stderr().write_all(rewritten.as_bytes())?;
+            }
+        }
+    })
+}
+
 #[derive(Debug)]
 enum Source {
     Script {
@@ -247,6 +664,17 @@ impl Source {
         }
     }
 
+    /// Where files named relative to the build root live on disk. Unlike
+    /// `root`, this never points into the temporary build directory.
+    fn files_root(&self) -> Result<&Path> {
+        match self {
+            Self::Script { script, .. } => Ok(script
+                .parent()
+                .context("cannot find the directory containing the script")?),
+            Self::Directory { root, .. } => Ok(root),
+        }
+    }
+
     fn script(&self) -> Result<&Path> {
         match self {
             Self::Script { script, .. } => script
@@ -315,6 +743,17 @@ impl Source {
         }
     }
 
+    fn keep_tempdir(&mut self) {
+        let tempdir = match self {
+            Self::Script { tempdir, .. } => tempdir,
+            Self::Directory { tempdir, .. } => tempdir,
+        };
+
+        if let Some(tempdir) = tempdir.get_mut() {
+            tempdir.keep = true;
+        }
+    }
+
     fn has_default_nix(&self) -> bool {
         match self {
             Self::Script { .. } => false,
@@ -322,33 +761,51 @@ impl Source {
         }
     }
 
-    fn hash<H: Hasher>(&self, hasher: &mut H) -> Result<()> {
+    /// Every file that goes into the hash, in order.
+    fn files(&self) -> Result<Vec<PathBuf>> {
         match self {
-            Self::Script { script, .. } => {
-                log::debug!("hashing {}", script.display());
-                hasher.write(
-                    fs::read_to_string(script)
-                        .context("could not read script contents")?
-                        .as_ref(),
-                )
-            }
+            Self::Script { script, .. } => Ok(vec![script.to_owned()]),
             Self::Directory { root, .. } => {
+                let mut out = Vec::new();
+
                 for path_res in WalkDir::new(root)
                     .min_depth(1)
                     .follow_links(true)
                     .sort_by_file_name()
                 {
                     let path = path_res.context("could not read directory entry")?;
-                    if path.file_type().is_dir() {
-                        continue;
+                    if !path.file_type().is_dir() {
+                        out.push(path.into_path());
                     }
+                }
+
+                Ok(out)
+            }
+        }
+    }
 
-                    log::debug!("hashing {}", path.path().display());
-                    hasher.write(path.file_name().as_bytes());
+    fn hash<H: Hasher>(&self, hasher: &mut H) -> Result<()> {
+        match self {
+            Self::Script { script, .. } => {
+                log::debug!("hashing {}", script.display());
+                hasher.write(
+                    fs::read_to_string(script)
+                        .context("could not read script contents")?
+                        .as_ref(),
+                )
+            }
+            Self::Directory { .. } => {
+                for path in self.files()? {
+                    log::debug!("hashing {}", path.display());
                     hasher.write(
-                        fs::read_to_string(path.path())
+                        path.file_name()
+                            .context("walked to a path without a file name")?
+                            .as_bytes(),
+                    );
+                    hasher.write(
+                        fs::read_to_string(&path)
                             .with_context(|| {
-                                format!("could not read {} in script source", path.path().display())
+                                format!("could not read {} in script source", path.display())
                             })?
                             .as_ref(),
                     );
@@ -360,6 +817,101 @@ impl Source {
     }
 }
 
+/// Work out where each runtime file comes from (relative to the root) and
+/// where it goes (relative to `RUNTIME_FILES_ROOT`.) Entries can be renamed
+/// with `src=dest`, unless a file with the whole name exists.
+fn runtime_files(root: &Path, entries: &[PathBuf]) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut out = Vec::new();
+
+    for entry in entries {
+        let raw = entry
+            .to_str()
+            .with_context(|| format!("`{}` is not valid UTF-8", entry.display()))?;
+
+        match raw.split_once('=') {
+            Some((src, dest)) if !root.join(entry).exists() => {
+                if src.is_empty() || dest.is_empty() {
+                    anyhow::bail!("`{}` needs a path on both sides of `=`", raw)
+                }
+                if src.contains(['*', '?', '[']) {
+                    anyhow::bail!("cannot rename `{}` because it is a glob pattern", src)
+                }
+
+                out.push((PathBuf::from(src), PathBuf::from(dest)));
+            }
+            _ => {
+                for path in expand_globs(root, std::slice::from_ref(entry))? {
+                    out.push((path.clone(), path));
+                }
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// Expand glob patterns (like `data/*.json` or `templates/**`) relative to
+/// the root. Paths without glob characters are kept as they are. Matches are
+/// sorted so the generated derivation does not depend on directory order.
+fn expand_globs(root: &Path, patterns: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let options = glob::MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: true,
+    };
+
+    let mut out = Vec::new();
+
+    for pattern in patterns {
+        let raw = pattern
+            .to_str()
+            .with_context(|| format!("`{}` is not valid UTF-8", pattern.display()))?;
+
+        if !raw.contains(['*', '?', '[']) {
+            out.push(pattern.to_owned());
+            continue;
+        }
+
+        let root_pattern = glob::Pattern::escape(
+            root.to_str()
+                .with_context(|| format!("`{}` is not valid UTF-8", root.display()))?,
+        );
+        // A trailing `**` only matches directories, but people writing
+        // `templates/**` mean everything inside.
+        let full = if raw.ends_with("**") {
+            format!("{root_pattern}/{raw}/*")
+        } else {
+            format!("{root_pattern}/{raw}")
+        };
+
+        let mut matches = Vec::new();
+        for entry in glob::glob_with(&full, options)
+            .with_context(|| format!("`{raw}` is not a valid glob pattern"))?
+        {
+            let path = entry.context("could not read a path while expanding a glob")?;
+            if path.is_dir() {
+                continue;
+            }
+
+            matches.push(
+                path.strip_prefix(root)
+                    .context("glob matched a path outside the root; this is a bug; please report")?
+                    .to_owned(),
+            );
+        }
+
+        if matches.is_empty() {
+            anyhow::bail!("`{}` did not match any files", raw);
+        }
+
+        matches.sort();
+        out.extend(matches);
+    }
+
+    out.dedup();
+    Ok(out)
+}
+
 /// When you run a build, Nix uses the directory name as part of the calculation
 /// for the final path in the store. That means that if we have random temporary
 /// directory names like `nix-script-a4beff` we'll bust the cache every time. We
@@ -368,6 +920,7 @@ impl Source {
 #[derive(Debug)]
 struct TempBuildRoot {
     dest: PathBuf,
+    keep: bool,
 }
 
 impl TempBuildRoot {
@@ -377,12 +930,17 @@ impl TempBuildRoot {
 
         log::trace!("created temporary directory {}", dest.display());
 
-        Ok(TempBuildRoot { dest })
+        Ok(TempBuildRoot { dest, keep: false })
     }
 }
 
 impl Drop for TempBuildRoot {
     fn drop(&mut self) {
+        if self.keep {
+            log::debug!("keeping temporary directory {:?}", &self.dest);
+            return;
+        }
+
         log::trace!("attempting to remove temporary directory {:?}", &self.dest);
 
         if let Err(err) = fs::remove_dir_all(&self.dest) {
@@ -394,3 +952,325 @@ impl Drop for TempBuildRoot {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod runtime_files {
+        use super::*;
+        use tempfile::tempdir;
+
+        #[test]
+        fn keeps_paths() {
+            let root = tempdir().unwrap();
+
+            assert_eq!(
+                vec![(PathBuf::from("data/a.json"), PathBuf::from("data/a.json"))],
+                runtime_files(root.path(), &[PathBuf::from("data/a.json")]).unwrap()
+            )
+        }
+
+        #[test]
+        fn renames() {
+            let root = tempdir().unwrap();
+
+            assert_eq!(
+                vec![(PathBuf::from("prod.toml"), PathBuf::from("config.toml"))],
+                runtime_files(root.path(), &[PathBuf::from("prod.toml=config.toml")]).unwrap()
+            )
+        }
+
+        #[test]
+        fn prefers_existing_files() {
+            let root = tempdir().unwrap();
+            fs::write(root.path().join("a=b"), "").unwrap();
+
+            assert_eq!(
+                vec![(PathBuf::from("a=b"), PathBuf::from("a=b"))],
+                runtime_files(root.path(), &[PathBuf::from("a=b")]).unwrap()
+            )
+        }
+
+        #[test]
+        fn cannot_rename_globs() {
+            let root = tempdir().unwrap();
+
+            let problem = runtime_files(root.path(), &[PathBuf::from("*.json=data")]).unwrap_err();
+            assert_eq!(
+                "cannot rename `*.json` because it is a glob pattern",
+                problem.to_string()
+            )
+        }
+    }
+
+    mod indexed_hash {
+        use super::*;
+        use std::time::{Duration, SystemTime};
+        use tempfile::tempdir;
+
+        #[test]
+        fn matches_hash() {
+            let root = tempdir().unwrap();
+            let cache = tempdir().unwrap();
+            let script = root.path().join("script.sh");
+            fs::write(&script, "#!build cp $SRC $OUT\n").unwrap();
+            fs::File::options()
+                .write(true)
+                .open(&script)
+                .unwrap()
+                .set_modified(SystemTime::now() - Duration::from_secs(60))
+                .unwrap();
+
+            let directives = Directives::from_file(Some("#!"), &script).unwrap();
+            let builder = Builder::from_directory(root.path(), &script).unwrap();
+            let index = Index::new(cache.path());
+
+            let expected = builder.hash(&directives).unwrap();
+            assert_eq!(expected, builder.indexed_hash(&directives, &index).unwrap());
+            // The second time around, the hash comes from the index.
+            assert_eq!(expected, builder.indexed_hash(&directives, &index).unwrap());
+        }
+    }
+
+    mod relocated {
+        use super::*;
+        use tempfile::tempdir;
+
+        #[test]
+        fn keeps_debug_options() {
+            let root = tempdir().unwrap();
+            let to = tempdir().unwrap();
+            let mut builder =
+                Builder::from_directory(root.path(), &root.path().join("script.sh")).unwrap();
+            builder.keep_build_dir();
+            builder.print_derivation();
+
+            let relocated = builder.relocated(to.path()).unwrap();
+
+            assert!(relocated.keep_build_dir);
+            assert!(relocated.print_derivation);
+        }
+
+        #[test]
+        fn builds_from_the_new_root() {
+            let root = tempdir().unwrap();
+            let to = tempdir().unwrap();
+            let builder =
+                Builder::from_directory(root.path(), &root.path().join("script.sh")).unwrap();
+
+            assert_eq!(
+                to.path(),
+                builder
+                    .relocated(to.path())
+                    .unwrap()
+                    .built_source()
+                    .unwrap()
+            );
+        }
+    }
+
+    mod built_source {
+        use super::*;
+        use tempfile::tempdir;
+
+        #[test]
+        fn isolated_copy_of_a_script() {
+            let root = tempdir().unwrap();
+            let cache = tempdir().unwrap();
+            let script = root.path().join("script.sh");
+            fs::write(&script, "echo hi").unwrap();
+
+            let mut builder = Builder::from_script(&script);
+            builder.source.isolate(cache.path(), "abc").unwrap();
+
+            assert_eq!(
+                cache.path().join("abc-script.sh-src"),
+                builder.built_source().unwrap()
+            );
+        }
+    }
+
+    mod latest_log_path {
+        use super::*;
+        use std::time::{Duration, SystemTime};
+        use tempfile::tempdir;
+
+        fn write_log(cache: &Path, name: &str, age: u64) {
+            let path = cache.join(".logs").join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, name).unwrap();
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(SystemTime::now() - Duration::from_secs(age))
+                .unwrap();
+        }
+
+        #[test]
+        fn prefers_the_current_version() {
+            let cache = tempdir().unwrap();
+            write_log(cache.path(), "abc-script.sh.log", 60);
+            write_log(cache.path(), "def-script.sh.log", 0);
+
+            let builder = Builder::from_script(Path::new("/project/script.sh"));
+
+            assert_eq!(
+                Some(cache.path().join(".logs/abc-script.sh.log")),
+                builder.latest_log_path(cache.path(), "abc").unwrap()
+            );
+        }
+
+        #[test]
+        fn falls_back_to_the_newest_log() {
+            let cache = tempdir().unwrap();
+            write_log(cache.path(), "abc-script.sh.log", 60);
+            write_log(cache.path(), "def-script.sh.log", 30);
+            write_log(cache.path(), "123-other-script.sh.log", 0);
+
+            let builder = Builder::from_script(Path::new("/project/script.sh"));
+
+            assert_eq!(
+                Some(cache.path().join(".logs/def-script.sh.log")),
+                builder.latest_log_path(cache.path(), "fff").unwrap()
+            );
+        }
+
+        #[test]
+        fn none_without_logs() {
+            let cache = tempdir().unwrap();
+
+            let builder = Builder::from_script(Path::new("/project/script.sh"));
+
+            assert_eq!(None, builder.latest_log_path(cache.path(), "abc").unwrap());
+        }
+    }
+
+    mod tee_to_log {
+        use super::*;
+        use tempfile::tempdir;
+
+        #[test]
+        fn copies_everything() {
+            let dir = tempdir().unwrap();
+            let path = dir.path().join("build.log");
+            let input = io::Cursor::new(b"building...\ndone\n".to_vec());
+
+            tee_to_log(input, File::create(&path).unwrap(), false, rewriter())
+                .join()
+                .unwrap()
+                .unwrap();
+
+            assert_eq!("building...\ndone\n", fs::read_to_string(&path).unwrap());
+        }
+
+        #[test]
+        fn rewrites_paths() {
+            let dir = tempdir().unwrap();
+            let path = dir.path().join("build.log");
+            let input = io::Cursor::new(b"script.hs:3:5: error:\n".to_vec());
+
+            tee_to_log(input, File::create(&path).unwrap(), false, rewriter())
+                .join()
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(
+                "/home/me/script.hs:3:5: error:\n",
+                fs::read_to_string(&path).unwrap()
+            );
+        }
+
+        fn rewriter() -> PathRewriter {
+            PathRewriter::new(Path::new("/home/me"), &[], &[PathBuf::from("script.hs")]).unwrap()
+        }
+    }
+
+    mod kept_build_tree {
+        use super::*;
+        use tempfile::tempdir;
+
+        #[test]
+        fn finds_directory_in_log() {
+            let dir = tempdir().unwrap();
+            let log = dir.path().join("build.log");
+            fs::write(
+                &log,
+                "error: builder failed\nnote: keeping build directory '/tmp/nix-build-x.drv-0'\n",
+            )
+            .unwrap();
+
+            assert_eq!(
+                Some("/tmp/nix-build-x.drv-0".into()),
+                kept_build_tree(&log).unwrap()
+            );
+        }
+
+        #[test]
+        fn none_without_note() {
+            let dir = tempdir().unwrap();
+            let log = dir.path().join("build.log");
+            fs::write(&log, "error: builder failed\n").unwrap();
+
+            assert_eq!(None, kept_build_tree(&log).unwrap());
+        }
+    }
+
+    mod expand_globs {
+        use super::*;
+        use tempfile::tempdir;
+
+        fn touch(root: &Path, path: &str) {
+            let full = root.join(path);
+            fs::create_dir_all(full.parent().unwrap()).unwrap();
+            fs::write(full, "").unwrap();
+        }
+
+        #[test]
+        fn keeps_literal_paths() {
+            let root = tempdir().unwrap();
+
+            assert_eq!(
+                vec![PathBuf::from("missing file")],
+                expand_globs(root.path(), &[PathBuf::from("missing file")]).unwrap()
+            )
+        }
+
+        #[test]
+        fn expands_stars_sorted() {
+            let root = tempdir().unwrap();
+            touch(root.path(), "data/b.json");
+            touch(root.path(), "data/a.json");
+            touch(root.path(), "data/c.txt");
+
+            assert_eq!(
+                vec![PathBuf::from("data/a.json"), PathBuf::from("data/b.json")],
+                expand_globs(root.path(), &[PathBuf::from("data/*.json")]).unwrap()
+            )
+        }
+
+        #[test]
+        fn expands_recursively_to_files() {
+            let root = tempdir().unwrap();
+            touch(root.path(), "templates/a");
+            touch(root.path(), "templates/nested/b");
+
+            assert_eq!(
+                vec![
+                    PathBuf::from("templates/a"),
+                    PathBuf::from("templates/nested/b")
+                ],
+                expand_globs(root.path(), &[PathBuf::from("templates/**")]).unwrap()
+            )
+        }
+
+        #[test]
+        fn fails_without_matches() {
+            let root = tempdir().unwrap();
+
+            let problem = expand_globs(root.path(), &[PathBuf::from("*.json")]).unwrap_err();
+            assert!(problem.to_string().contains("did not match any files"))
+        }
+    }
+}
@@ -1,28 +1,62 @@
 mod inputs;
+mod nix;
 
 use crate::clean_path::clean_path;
 use anyhow::{Context, Result};
 use inputs::Inputs;
+use nix::{store_name, Indented, Nix};
+use nix_script_directives::env::EnvVar;
 use nix_script_directives::expr::Expr;
-use std::collections::BTreeSet;
+use nix_script_directives::wrapper::WrapperArg;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{self, Display};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+
+/// Which conventions the rendered derivation follows.
+#[derive(Debug)]
+enum Format {
+    /// Imports nixpkgs itself, so `nix-build` can build it as is.
+    Standalone,
+    /// Takes its dependencies as arguments, like packages in nixpkgs.
+    CallPackage { version: String },
+}
 
 #[derive(Debug)]
 pub struct Derivation {
+    format: Format,
     inputs: Inputs,
+    // How we import nixpkgs, and the arguments we import it with.
+    nixpkgs: String,
+    nixpkgs_options: String,
 
     name: String,
     src: PathBuf,
     root: PathBuf,
+    // When set, only these files (relative to the root) end up in `src`.
+    src_files: Option<BTreeSet<PathBuf>>,
+    // When set, `src` is built from this script text instead of the root.
+    embedded_script: Option<String>,
 
     build_command: String,
+    check_command: Option<String>,
+    install_command: Option<String>,
+    // stdenv hooks like `preBuild`, by name.
+    hooks: BTreeMap<&'static str, String>,
 
     build_inputs: BTreeSet<Expr>,
 
     interpreter: Option<(String, Option<String>)>,
     runtime_inputs: BTreeSet<Expr>,
-    runtime_files: BTreeSet<PathBuf>,
+    // Where each runtime file goes (relative to `RUNTIME_FILES_ROOT`), and
+    // where it comes from (relative to the root.)
+    runtime_files: BTreeMap<PathBuf, PathBuf>,
+    binaries: Vec<String>,
+
+    strip_indicators: Vec<String>,
+
+    env: Vec<EnvVar>,
+    build_env: Vec<EnvVar>,
+    wrapper_args: Vec<WrapperArg>,
 }
 
 impl Derivation {
@@ -48,14 +82,16 @@ impl Derivation {
             )?,
         };
 
+        let nixpkgs = format!("import <nixpkgs> {final_nixpkgs_options}");
+
         Ok(Self {
+            format: Format::Standalone,
             inputs: Inputs::from(vec![
-                (
-                    "pkgs".into(),
-                    Some(format!("import <nixpkgs> {final_nixpkgs_options}")),
-                ),
+                ("pkgs".into(), Some(nixpkgs.clone())),
                 ("makeWrapper".into(), Some("pkgs.makeWrapper".into())),
             ]),
+            nixpkgs,
+            nixpkgs_options: final_nixpkgs_options.to_string(),
             name: src
                 .file_name()
                 .and_then(|name| name.to_str())
@@ -63,11 +99,21 @@ impl Derivation {
                 .context("could not determine derivation name from input path")?,
             src: src.to_owned(),
             root: clean_path(root).context("could not determine path to source for derivation")?,
+            src_files: None,
+            embedded_script: None,
             build_command: build_command.to_owned(),
+            check_command: None,
+            install_command: None,
+            hooks: BTreeMap::new(),
             build_inputs: BTreeSet::new(),
             interpreter: None,
             runtime_inputs: BTreeSet::new(),
-            runtime_files: BTreeSet::new(),
+            runtime_files: BTreeMap::new(),
+            binaries: Vec::new(),
+            strip_indicators: Vec::new(),
+            env: Vec::new(),
+            build_env: Vec::new(),
+            wrapper_args: Vec::new(),
         })
     }
 
@@ -82,6 +128,18 @@ impl Derivation {
         }
     }
 
+    pub fn set_check_command(&mut self, check_command: &str) {
+        self.check_command = Some(check_command.to_owned());
+    }
+
+    pub fn set_install_command(&mut self, install_command: &str) {
+        self.install_command = Some(install_command.to_owned());
+    }
+
+    pub fn set_hook(&mut self, name: &'static str, script: &str) {
+        self.hooks.insert(name, script.to_owned());
+    }
+
     pub fn set_interpreter(&mut self, interpreter: &str) -> Result<()> {
         let trimmed = interpreter.trim();
         let mut words = trimmed.split(' ');
@@ -117,127 +175,696 @@ impl Derivation {
         }
     }
 
-    pub fn add_runtime_files(&mut self, runtime_files: Vec<PathBuf>) {
-        for runtime_file in runtime_files {
-            self.runtime_files.insert(runtime_file);
+    /// Add runtime files as `(source, destination)` pairs. Two files can't
+    /// end up in the same place, or one inside the other.
+    pub fn add_runtime_files(&mut self, runtime_files: Vec<(PathBuf, PathBuf)>) -> Result<()> {
+        for (src, dest) in runtime_files {
+            if !dest
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+            {
+                anyhow::bail!(
+                    "`{}` must be a relative path without `.` or `..` to be installed as a runtime file",
+                    dest.display()
+                )
+            }
+
+            if let Some(existing) = self.runtime_files.get(&dest) {
+                if *existing == src {
+                    continue;
+                }
+
+                anyhow::bail!(
+                    "`{}` and `{}` would both be installed as runtime file `{}`",
+                    existing.display(),
+                    src.display(),
+                    dest.display()
+                )
+            }
+
+            if let Some((other_dest, other_src)) = self
+                .runtime_files
+                .iter()
+                .find(|(other, _)| dest.starts_with(other) || other.starts_with(&dest))
+            {
+                anyhow::bail!(
+                    "runtime files `{}` (installed as `{}`) and `{}` (installed as `{}`) overlap",
+                    other_src.display(),
+                    other_dest.display(),
+                    src.display(),
+                    dest.display()
+                )
+            }
+
+            self.runtime_files.insert(dest, src);
         }
+
+        Ok(())
     }
-}
 
-impl Display for Derivation {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        write!(
-            f,
-            "{}:\npkgs.stdenv.mkDerivation {{\n  name = \"{}\";\n  src = {};\n\n",
-            self.inputs,
-            self.name,
-            self.root.display(),
-        )?;
+    /// Only put the script and the given files (relative to the root) in
+    /// `src` instead of the whole root.
+    pub fn only_include(&mut self, files: Vec<PathBuf>) {
+        let src_files = self.src_files.get_or_insert_with(BTreeSet::new);
+        src_files.insert(self.src.clone());
+        src_files.extend(files);
+    }
 
-        if !self.build_inputs.is_empty() {
-            write!(f, "  buildInputs = with pkgs; ")?;
-            fmt_list(f, &self.build_inputs)?;
-            writeln!(f, ";")?;
-        }
-
-        // build phase
-        write!(
-            f,
-            "  buildPhase = ''\n    SRC={}\n\n    mkdir bin\n    OUT=bin/{}\n\n",
-            self.src.display(),
-            self.name,
-        )?;
+    pub fn add_binaries(&mut self, binaries: Vec<String>) {
+        self.binaries.extend(binaries);
+    }
+
+    /// Put the script's contents in the derivation itself, so it does not
+    /// need the root to build.
+    pub fn embed_script(&mut self, contents: &str) {
+        self.embedded_script = Some(contents.to_owned());
+    }
+
+    /// Render the derivation for `callPackage`, taking everything it needs
+    /// as arguments instead of importing nixpkgs.
+    pub fn use_call_package(&mut self, version: &str) {
+        if self.nixpkgs_options != "{ }" {
+            log::warn!(
+                "callPackage derivations can't set the nixpkgs config; leaving out `{}`",
+                self.nixpkgs_options
+            );
+        }
+
+        self.format = Format::CallPackage {
+            version: version.to_owned(),
+        };
+    }
+
+    pub fn set_strip_indicators(&mut self, indicators: Vec<String>) {
+        self.strip_indicators = indicators;
+    }
+
+    pub fn add_env(&mut self, env: Vec<EnvVar>) {
+        self.env.extend(env);
+    }
+
+    pub fn add_build_env(&mut self, build_env: Vec<EnvVar>) {
+        self.build_env.extend(build_env);
+    }
+
+    pub fn add_wrapper_args(&mut self, wrapper_args: Vec<WrapperArg>) {
+        self.wrapper_args.extend(wrapper_args);
+    }
+}
+
+impl Derivation {
+    fn build_phase(&self) -> Indented {
+        // Since we replace the whole phase, we have to run the hooks
+        // ourselves.
+        let mut phase = Indented::new();
+        phase.push_line("runHook preBuild");
+        phase.push_line("");
+        phase.push_line(&format!(
+            "SRC={}",
+            shell_words::quote(&self.src.display().to_string())
+        ));
+        if !self.strip_indicators.is_empty() {
+            let mut sed = String::from("sed -i");
+            for indicator in &self.strip_indicators {
+                let program = format!("/^{}/s/.*//", escape_sed(indicator));
+                sed.push_str(&format!(" -e {}", shell_words::quote(&program)));
+            }
+            phase.push_line(&format!("{sed} \"$SRC\""));
+        }
+        phase.push_line("");
+        phase.push_line("mkdir bin");
+        phase.push_line(&format!("OUT=bin/{}", shell_words::quote(&self.name)));
+        phase.push_line("");
+        if !self.build_env.is_empty() {
+            for var in &self.build_env {
+                phase.push_line(&export(var));
+            }
+            phase.push_line("");
+        }
         if self.build_command.is_empty() {
-            write!(f, "    echo build command is not set\n    exit 1\n")?;
+            phase.push_line("echo build command is not set");
+            phase.push_line("exit 1");
         } else {
-            writeln!(f, "    {}", self.build_command)?;
+            phase.push_line(&self.build_command);
         }
-        write!(f, "  '';\n\n")?;
+        phase.push_line("");
+        phase.push_line("runHook postBuild");
 
-        // install phase
-        if !self.runtime_inputs.is_empty() {
-            write!(f, "  nativeBuildInputs = with pkgs; ")?;
-            fmt_list(f, &self.runtime_inputs)?;
-            writeln!(f, ";")?;
-        }
+        phase
+    }
+
+    fn install_phase(&self) -> Indented {
+        let name = shell_words::quote(&self.name);
+        let hidden = shell_words::quote(&format!(".{}", self.name)).into_owned();
 
-        write!(
-            f,
-            "  installPhase = ''\n    mkdir -p $out\n    mv bin $out/bin"
-        )?;
+        let mut phase = Indented::new();
+        phase.push_line("runHook preInstall");
+        phase.push_line("");
+        match &self.install_command {
+            Some(install_command) => phase.push_line(install_command),
+            None => {
+                phase.push_line("mkdir -p $out");
+                phase.push_line("mv bin $out/bin");
+            }
+        }
 
         if !self.runtime_files.is_empty() {
-            let target: PathBuf = ["$out", "usr", "share", &self.name].iter().collect();
-            write!(f, "\n\n    mkdir -p {}", target.display())?;
-            for file in &self.runtime_files {
-                write!(f, "\n    mv {} {}", &file.display(), target.display())?;
+            phase.push_line("");
+            phase.push_line(&format!("mkdir -p $out/usr/share/{name}"));
+            for (dest, src) in &self.runtime_files {
+                if let Some(parent) = dest.parent().filter(|p| !p.as_os_str().is_empty()) {
+                    phase.push_line(&format!(
+                        "mkdir -p $out/usr/share/{name}/{}",
+                        shell_words::quote(&parent.display().to_string())
+                    ));
+                }
+                phase.push_line(&format!(
+                    "cp -r {} $out/usr/share/{name}/{}",
+                    shell_words::quote(&src.display().to_string()),
+                    shell_words::quote(&dest.display().to_string()),
+                ));
             }
         }
 
-        write!(
-            f,
-            "\n\n    source ${{makeWrapper}}/nix-support/setup-hook\n    "
-        )?;
+        // With `callPackage`, `makeWrapper` is in `nativeBuildInputs` and
+        // sets itself up.
+        phase.push_line("");
+        if let Format::Standalone = self.format {
+            phase.push_str("source ");
+            phase.interpolate(Nix::raw("makeWrapper"));
+            phase.push_line("/nix-support/setup-hook");
+        }
 
         if let Some((command, maybe_args)) = &self.interpreter {
-            write!(
-                f,
-                "mv $out/bin/{} $out/bin/.{}\n    makeWrapper $(command -v {}) $out/bin/{} \\\n",
-                self.name, self.name, command, self.name
-            )?;
-
-            if let Some(args) = maybe_args {
-                write!(
-                    f,
-                    "        --add-flags \"{} $out/bin/.{}\" ",
-                    args, self.name
-                )?
-            } else {
-                write!(f, "        --add-flags \"$out/bin/.{}\"", self.name)?;
+            phase.push_line(&format!("mv $out/bin/{name} $out/bin/{hidden}"));
+            phase.push_line(&format!(
+                "makeWrapper $(command -v {}) $out/bin/{name} \\",
+                shell_words::quote(command)
+            ));
+
+            // `--add-flags` is split again by the wrapper, so the path has
+            // to be quoted inside the (double-quoted) flags.
+            let script = format!("\\\"$out/bin/\\\"{}", escape_double_quoted(&hidden));
+            match maybe_args {
+                Some(args) => phase.push_str(&format!("    --add-flags \"{args} {script}\"")),
+                None => phase.push_str(&format!("    --add-flags \"{script}\"")),
             }
         } else {
-            write!(
-                f,
-                "wrapProgram $out/bin/{} --argv0 {}",
-                self.name, self.name
-            )?
+            phase.push_str(&format!("wrapProgram $out/bin/{name} --argv0 {name}"));
+        }
+
+        self.push_wrapper_flags(&mut phase);
+        phase.push_line("");
+
+        for binary in &self.binaries {
+            let binary = shell_words::quote(binary);
+            phase.push_str(&format!("wrapProgram $out/bin/{binary} --argv0 {binary}"));
+            self.push_wrapper_flags(&mut phase);
+            phase.push_line("");
         }
 
+        phase.push_line("");
+        phase.push_line("runHook postInstall");
+
+        phase
+    }
+
+    /// The flags every wrapper we make gets, as continuation lines.
+    fn push_wrapper_flags(&self, phase: &mut Indented) {
+        let name = shell_words::quote(&self.name);
+
         if !self.runtime_files.is_empty() {
-            write!(
-                f,
-                " \\\n        --set RUNTIME_FILES_ROOT $out/usr/share/{}",
-                self.name
-            )?;
+            phase.push_str(&format!(
+                " \\\n    --set RUNTIME_FILES_ROOT $out/usr/share/{name}"
+            ));
         }
 
-        write!(f, " \\\n        --set SCRIPT_FILE {}", self.name)?;
+        // nix-script sets the real location when it runs the script, so
+        // this is only a fallback for running the wrapper directly.
+        phase.push_str(&format!(" \\\n    --set-default SCRIPT_FILE {name}"));
+
+        for var in &self.env {
+            phase.push_str(&format!(" \\\n    {}", wrapper_args(var)));
+        }
+
+        for arg in &self.wrapper_args {
+            phase.push_str(&format!(" \\\n    {}", arg.flag));
+            for word in &arg.args {
+                phase.push_str(" ");
+                if expands_while_building(arg, word) {
+                    self.push_expanded_word(phase, word);
+                } else {
+                    phase.push_str(&shell_words::quote(word));
+                }
+            }
+        }
 
         if !self.runtime_inputs.is_empty() {
-            write!(
-                f,
-                " \\\n        --prefix PATH : ${{with pkgs; lib.makeBinPath "
-            )?;
-            fmt_list(f, &self.runtime_inputs)?;
-            write!(f, "}}")?;
+            phase.push_str(" \\\n    --prefix PATH : ");
+            phase.interpolate(self.with_pkgs(Nix::apply(
+                Nix::raw("lib.makeBinPath"),
+                list(&self.runtime_inputs),
+            )));
+        }
+    }
+
+    /// Nix can only copy paths with certain characters in their name to the
+    /// store, so we give the source a fixed name when the root has others.
+    fn src(&self) -> Nix {
+        if let Some(contents) = &self.embedded_script {
+            return Nix::apply(
+                self.pkgs_attr("writeTextFile"),
+                Nix::Attrs(vec![
+                    ("name".into(), Nix::str(store_name(&self.name))),
+                    (
+                        "destination".into(),
+                        Nix::str(format!("/{}", self.src.display())),
+                    ),
+                    // An indented string would strip leading whitespace.
+                    ("text".into(), Nix::str(contents)),
+                ]),
+            );
+        }
+
+        let path = Nix::Path(self.root.clone());
+
+        if let Some(files) = &self.src_files {
+            let fileset = files
+                .iter()
+                .map(|file| Nix::Path(self.root.join(file)))
+                .collect();
+
+            return Nix::apply(
+                self.pkgs_attr("lib.fileset.toSource"),
+                Nix::Attrs(vec![
+                    ("root".into(), path),
+                    (
+                        "fileset".into(),
+                        Nix::apply(self.pkgs_attr("lib.fileset.unions"), Nix::List(fileset)),
+                    ),
+                ]),
+            );
+        }
+
+        match self.root.file_name().and_then(|name| name.to_str()) {
+            Some(name) if store_name(name) != name => Nix::apply(
+                Nix::raw("builtins.path"),
+                Nix::Attrs(vec![
+                    ("name".into(), Nix::str("source")),
+                    ("path".into(), path),
+                ]),
+            ),
+            _ => path,
+        }
+    }
+
+    /// Push a wrapper argument that refers to something only known while
+    /// building, like `$out/lib` or `${lib.makeLibraryPath [ zlib ]}`. The
+    /// shell expands it in double quotes, and `${...}` is a Nix interpolation
+    /// that can name packages like our inputs do.
+    fn push_expanded_word(&self, phase: &mut Indented, word: &str) {
+        phase.push_str("\"");
+
+        let (parts, rest) = split_interpolations(word);
+        for (text, expr) in parts {
+            phase.push_str(&escape_expanded(text));
+            phase.interpolate(self.with_pkgs(Nix::raw(expr)));
         }
+        phase.push_str(&escape_expanded(rest));
 
-        write!(f, "\n  '';\n")?;
+        phase.push_str("\"");
+    }
+
+    /// An attribute of nixpkgs: through `pkgs` when we import it ourselves,
+    /// or directly when it's one of our arguments.
+    fn pkgs_attr(&self, attr: &str) -> Nix {
+        match self.format {
+            Format::Standalone => Nix::raw(format!("pkgs.{attr}")),
+            Format::CallPackage { .. } => Nix::raw(attr),
+        }
+    }
 
-        write!(f, "}}")
+    /// An expression that refers to packages by name, like our inputs.
+    fn with_pkgs(&self, body: Nix) -> Nix {
+        match self.format {
+            Format::Standalone => Nix::with(Nix::raw("pkgs"), body),
+            Format::CallPackage { .. } => body,
+        }
+    }
+
+    /// The arguments `callPackage` has to give us: `lib` and `stdenv`, the
+    /// wrapper, and whatever our inputs start from.
+    fn call_package_inputs(&self) -> Inputs {
+        let mut inputs = Inputs::from(vec![
+            ("lib".into(), None),
+            ("stdenv".into(), None),
+            ("makeWrapper".into(), None),
+        ]);
+
+        if self.embedded_script.is_some() {
+            inputs.insert("writeTextFile".into(), None);
+        }
+
+        for input in self.build_inputs.iter().chain(&self.runtime_inputs) {
+            match input.head() {
+                Some(head) => inputs.insert(head, None),
+                None => log::warn!(
+                    "could not tell which package `{input}` needs; add it to the arguments by hand"
+                ),
+            }
+        }
+
+        // Packages named in wrapper arguments, like `zlib` in
+        // `${lib.makeLibraryPath [ zlib ]}`.
+        for arg in &self.wrapper_args {
+            for word in arg
+                .args
+                .iter()
+                .filter(|word| expands_while_building(arg, word))
+            {
+                for (_, source) in split_interpolations(word).0 {
+                    match source.parse::<Expr>() {
+                        Ok(expr) => {
+                            for name in expr.identifiers() {
+                                inputs.insert(name, None)
+                            }
+                        }
+                        Err(err) => log::warn!(
+                            "could not tell which packages `{source}` needs ({err}); add them to the arguments by hand"
+                        ),
+                    }
+                }
+            }
+        }
+
+        inputs
+    }
+
+    /// A `shell.nix` to go next to this derivation (as `default.nix`), with
+    /// the same build and runtime inputs.
+    pub fn shell(&self) -> String {
+        let shell = Nix::lambda(
+            format!("{{ pkgs ? {} }}", self.nixpkgs),
+            Nix::apply(
+                Nix::raw("pkgs.mkShell"),
+                Nix::Attrs(vec![(
+                    "inputsFrom".into(),
+                    Nix::List(vec![Nix::raw("(import ./default.nix { inherit pkgs; })")]),
+                )]),
+            ),
+        );
+
+        format!("{shell}\n")
+    }
+
+    /// A `flake.nix` exposing this derivation (as `default.nix`) and its
+    /// shell (as `shell.nix`) for the common systems.
+    pub fn flake(&self) -> String {
+        let per_system = |file: &str| {
+            Nix::apply(
+                Nix::raw("forAllSystems"),
+                Nix::lambda(
+                    "system",
+                    Nix::Attrs(vec![(
+                        "default".into(),
+                        Nix::apply(
+                            Nix::raw(format!("import ./{file}")),
+                            Nix::Attrs(vec![(
+                                "pkgs".into(),
+                                Nix::raw(format!(
+                                    "import nixpkgs ({{ inherit system; }} // {})",
+                                    self.nixpkgs_options
+                                )),
+                            )]),
+                        ),
+                    )]),
+                ),
+            )
+        };
+
+        let systems = [
+            "x86_64-linux",
+            "aarch64-linux",
+            "x86_64-darwin",
+            "aarch64-darwin",
+        ];
+
+        let flake = Nix::Attrs(vec![
+            (
+                "description".into(),
+                Nix::str(format!("{} (ejected from nix-script)", self.name)),
+            ),
+            (
+                "inputs.nixpkgs.url".into(),
+                Nix::str("github:NixOS/nixpkgs/nixpkgs-unstable"),
+            ),
+            (
+                "outputs".into(),
+                Nix::lambda(
+                    "{ self, nixpkgs }",
+                    Nix::let_in(
+                        vec![(
+                            "forAllSystems".into(),
+                            Nix::apply(
+                                Nix::raw("nixpkgs.lib.genAttrs"),
+                                Nix::List(systems.into_iter().map(Nix::str).collect()),
+                            ),
+                        )],
+                        Nix::Attrs(vec![
+                            ("packages".into(), per_system("default.nix")),
+                            ("devShells".into(), per_system("shell.nix")),
+                        ]),
+                    ),
+                ),
+            ),
+        ]);
+
+        format!("{flake}\n")
     }
 }
 
-fn fmt_list(f: &mut fmt::Formatter<'_>, exprs: &BTreeSet<Expr>) -> Result<(), fmt::Error> {
-    write!(f, "[")?;
-    for expr in exprs {
-        if !expr.is_leaf() {
-            write!(f, " ({expr})")?;
-        } else {
-            write!(f, " {expr}")?;
+impl Display for Derivation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let mut attrs = match &self.format {
+            Format::Standalone => vec![("name".to_owned(), Nix::str(store_name(&self.name)))],
+            Format::CallPackage { version } => vec![
+                ("pname".to_owned(), Nix::str(store_name(&self.name))),
+                ("version".to_owned(), Nix::str(version)),
+            ],
+        };
+        attrs.push(("src".to_owned(), self.src()));
+
+        if !self.build_inputs.is_empty() {
+            attrs.push((
+                "buildInputs".into(),
+                self.with_pkgs(list(&self.build_inputs)),
+            ));
+        }
+
+        for (name, script) in &self.hooks {
+            let mut hook = Indented::new();
+            hook.push_line(script);
+            attrs.push((name.to_string(), Nix::Indented(hook)));
+        }
+
+        attrs.push(("buildPhase".into(), Nix::Indented(self.build_phase())));
+
+        match self.format {
+            Format::Standalone if !self.runtime_inputs.is_empty() => {
+                attrs.push((
+                    "nativeBuildInputs".into(),
+                    Nix::with(Nix::raw("pkgs"), list(&self.runtime_inputs)),
+                ));
+            }
+            Format::Standalone => {}
+            Format::CallPackage { .. } => {
+                let mut native = vec![Nix::raw("makeWrapper")];
+                native.extend(self.runtime_inputs.iter().map(Nix::expr));
+                attrs.push(("nativeBuildInputs".into(), Nix::List(native)));
+            }
+        }
+
+        attrs.push(("installPhase".into(), Nix::Indented(self.install_phase())));
+
+        // The check runs after install and fixup, so it sees the wrapped
+        // binary the way users will.
+        if let Some(check_command) = &self.check_command {
+            let mut check_phase = Indented::new();
+            check_phase.push_line("runHook preInstallCheck");
+            check_phase.push_line("");
+            check_phase.push_line(&format!(
+                "OUT=\"$out/bin/\"{}",
+                shell_words::quote(&self.name)
+            ));
+            check_phase.push_line(check_command);
+            check_phase.push_line("");
+            check_phase.push_line("runHook postInstallCheck");
+
+            attrs.push(("doInstallCheck".into(), Nix::raw("true")));
+            attrs.push(("installCheckPhase".into(), Nix::Indented(check_phase)));
+        }
+
+        let inputs = match self.format {
+            Format::Standalone => self.inputs.to_string(),
+            Format::CallPackage { .. } => {
+                attrs.push((
+                    "meta".into(),
+                    Nix::Attrs(vec![("mainProgram".into(), Nix::str(&self.name))]),
+                ));
+
+                self.call_package_inputs().to_string()
+            }
+        };
+
+        let derivation = Nix::lambda(
+            inputs,
+            Nix::apply(self.pkgs_attr("stdenv.mkDerivation"), Nix::Attrs(attrs)),
+        );
+
+        write!(f, "{derivation}")
+    }
+}
+
+/// A shell command exporting a variable in a build phase.
+fn export(var: &EnvVar) -> String {
+    match var {
+        EnvVar::Set { name, value } => format!("export {}={}", name, shell_words::quote(value)),
+        EnvVar::Prefix {
+            name,
+            separator,
+            value,
+        } => format!(
+            "export {}={}${{{}:+{}\"${}\"}}",
+            name,
+            shell_words::quote(value),
+            name,
+            shell_words::quote(separator),
+            name
+        ),
+        EnvVar::Suffix {
+            name,
+            separator,
+            value,
+        } => format!(
+            "export {}=${{{}:+\"${}\"{}}}{}",
+            name,
+            name,
+            name,
+            shell_words::quote(separator),
+            shell_words::quote(value)
+        ),
+    }
+}
+
+/// `makeWrapper` flags whose arguments name things only known while
+/// building, like `$out`. Everything else (`--run`, `--add-flags` and so on)
+/// ends up in the wrapper as is and is expanded when it runs.
+const EXPANDED_FLAGS: &[&str] = &["--prefix", "--suffix", "--set", "--set-default", "--chdir"];
+
+fn expands_while_building(arg: &WrapperArg, word: &str) -> bool {
+    EXPANDED_FLAGS.contains(&arg.flag.as_str()) && word.contains('$')
+}
+
+/// Split a word into the text before each `${...}` with the expression inside
+/// it, plus whatever text is left at the end.
+fn split_interpolations(word: &str) -> (Vec<(&str, &str)>, &str) {
+    let mut parts = Vec::new();
+    let mut rest = word;
+
+    while let Some(start) = rest.find("${") {
+        let body = &rest[start + 2..];
+        let mut depth = 0;
+        let end = body.char_indices().find_map(|(index, c)| match c {
+            '{' => {
+                depth += 1;
+                None
+            }
+            '}' if depth == 0 => Some(index),
+            '}' => {
+                depth -= 1;
+                None
+            }
+            _ => None,
+        });
+
+        match end {
+            Some(end) => {
+                parts.push((&rest[..start], body[..end].trim()));
+                rest = &body[end + 1..];
+            }
+            None => break,
+        }
+    }
+
+    (parts, rest)
+}
+
+/// Like `escape_double_quoted`, but leaves `$` for the shell to expand.
+fn escape_expanded(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '"' | '\\' | '`') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Arguments to `makeWrapper` setting a variable at runtime.
+fn wrapper_args(var: &EnvVar) -> String {
+    match var {
+        EnvVar::Set { name, value } => format!("--set {} {}", name, shell_words::quote(value)),
+        EnvVar::Prefix {
+            name,
+            separator,
+            value,
+        } => format!(
+            "--prefix {} {} {}",
+            name,
+            shell_words::quote(separator),
+            shell_words::quote(value)
+        ),
+        EnvVar::Suffix {
+            name,
+            separator,
+            value,
+        } => format!(
+            "--suffix {} {} {}",
+            name,
+            shell_words::quote(separator),
+            shell_words::quote(value)
+        ),
+    }
+}
+
+/// Escape characters that have a special meaning in a basic `sed` regex, or
+/// that would end the address.
+fn escape_sed(literal: &str) -> String {
+    let mut out = String::with_capacity(literal.len());
+    for c in literal.chars() {
+        if matches!(c, '\\' | '/' | '.' | '*' | '[' | ']' | '^' | '$') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Escape text for use inside double quotes in a shell script.
+fn escape_double_quoted(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '"' | '$' | '`') {
+            out.push('\\');
         }
+        out.push(c);
     }
-    write!(f, " ]")
+    out
+}
+
+fn list(exprs: &BTreeSet<Expr>) -> Nix {
+    Nix::List(exprs.iter().map(Nix::expr).collect())
 }
 
 #[cfg(test)]
@@ -292,5 +919,506 @@ mod tests {
 
             assert_no_errors(&derivation.to_string());
         }
+
+        #[test]
+        fn with_check_command() {
+            let root = PathBuf::from("/");
+            let path = PathBuf::from("X");
+            let mut derivation = Derivation::new(&root, &path, "mv $SRC $DEST", None).unwrap();
+            derivation.set_check_command("$OUT --self-test");
+
+            let out = derivation.to_string();
+            assert!(out.contains("doInstallCheck = true;"));
+            assert!(out.contains("OUT=\"$out/bin/\"X\n    $OUT --self-test\n"));
+            assert!(out.find("installPhase").unwrap() < out.find("installCheckPhase").unwrap());
+            assert_no_errors(&out);
+        }
+
+        #[test]
+        fn with_hooks() {
+            let root = PathBuf::from("/");
+            let path = PathBuf::from("X");
+            let mut derivation = Derivation::new(&root, &path, "mv $SRC $DEST", None).unwrap();
+            derivation.set_hook("preBuild", "echo '${generated}' > gen.h");
+            derivation.set_hook("postInstall", "cp x.1 $out/share/man");
+
+            let out = derivation.to_string();
+            assert!(out.contains("preBuild = ''\n    echo ${\"'\"}''${generated}' > gen.h\n  '';"));
+            assert!(out.contains("postInstall = ''\n    cp x.1 $out/share/man\n  '';"));
+            assert!(out.contains("runHook preBuild"));
+            assert!(out.contains("runHook postInstall"));
+            assert_no_errors(&out);
+        }
+
+        #[test]
+        fn with_install_command() {
+            let root = PathBuf::from("/");
+            let path = PathBuf::from("X");
+            let mut derivation = Derivation::new(&root, &path, "mv $SRC $DEST", None).unwrap();
+            derivation.set_install_command("install -Dm755 bin/X $out/bin/X");
+
+            let out = derivation.to_string();
+            assert!(out.contains("install -Dm755 bin/X $out/bin/X"));
+            assert!(!out.contains("mv bin $out/bin"));
+            assert!(out.contains("wrapProgram $out/bin/X"));
+            assert_no_errors(&out);
+        }
+
+        #[test]
+        fn with_binaries() {
+            let root = PathBuf::from("/");
+            let path = PathBuf::from("X");
+            let mut derivation = Derivation::new(&root, &path, "mv $SRC $DEST", None).unwrap();
+            derivation.add_runtime_inputs(vec![("jq").parse().unwrap()]);
+            derivation.add_binaries(vec!["helper".into()]);
+
+            let out = derivation.to_string();
+            assert!(out.contains("wrapProgram $out/bin/X --argv0 X"));
+            assert!(out.contains("wrapProgram $out/bin/helper --argv0 helper"));
+            assert_eq!(2, out.matches("--prefix PATH").count());
+            assert_no_errors(&out);
+        }
+
+        #[test]
+        fn with_strip_indicators() {
+            let root = PathBuf::from("/");
+            let path = PathBuf::from("X");
+            let mut derivation = Derivation::new(&root, &path, "mv $SRC $DEST", None).unwrap();
+            derivation.set_strip_indicators(vec!["#!".into(), "--".into()]);
+
+            let out = derivation.to_string();
+            assert!(out.contains("sed -i -e '/^#!/s/.*//' -e '/^--/s/.*//' \"$SRC\""));
+            assert_no_errors(&out);
+        }
+    }
+
+    mod script_file {
+        use super::*;
+
+        #[test]
+        fn defaults_to_name() {
+            let root = PathBuf::from("/");
+            let path = PathBuf::from("X");
+            let derivation = Derivation::new(&root, &path, "mv $SRC $DEST", None).unwrap();
+
+            assert!(derivation
+                .to_string()
+                .contains("--set-default SCRIPT_FILE X"));
+        }
+    }
+
+    mod call_package {
+        use super::*;
+
+        /// The names of the function arguments, as Nix sees them.
+        fn arguments(src: &str) -> Vec<String> {
+            use rnix::ast::{Expr, Param};
+
+            let lambda = match rnix::Root::parse(src).tree().expr().unwrap() {
+                Expr::Lambda(lambda) => lambda,
+                other => panic!("expected a function, got {other:?}"),
+            };
+            match lambda.param().unwrap() {
+                Param::Pattern(pattern) => pattern
+                    .pat_entries()
+                    .map(|entry| entry.ident().unwrap().to_string())
+                    .collect(),
+                other => panic!("expected a pattern, got {other:?}"),
+            }
+        }
+
+        #[test]
+        fn takes_packages_from_wrapper_args_as_arguments() {
+            let root = PathBuf::from("./.");
+            let path = PathBuf::from("X");
+            let mut derivation = Derivation::new(&root, &path, "mv $SRC $DEST", None).unwrap();
+            derivation.add_wrapper_args(
+                WrapperArg::parse_line(
+                    "--prefix LD_LIBRARY_PATH : '${lib.makeLibraryPath [ zlib openssl ]}'",
+                )
+                .unwrap(),
+            );
+            derivation.use_call_package("1.0");
+
+            let out = derivation.to_string();
+            assert_no_errors(&out);
+            assert_eq!(
+                vec!["lib", "makeWrapper", "openssl", "stdenv", "zlib"],
+                arguments(&out)
+            );
+            assert!(out.contains("\"${lib.makeLibraryPath [ zlib openssl ]}\""));
+        }
+
+        #[test]
+        fn takes_inputs_as_arguments() {
+            let root = PathBuf::from("./.");
+            let path = PathBuf::from("X");
+            let mut derivation = Derivation::new(&root, &path, "mv $SRC $DEST", None).unwrap();
+            derivation.add_build_inputs(vec![
+                ("haskellPackages.ghcWithPackages (ps: [ ps.text ])")
+                    .parse()
+                    .unwrap(),
+            ]);
+            derivation.add_runtime_inputs(vec![("jq").parse().unwrap()]);
+            derivation.use_call_package("1.0");
+
+            let out = derivation.to_string();
+            assert!(out.starts_with(
+                "{ haskellPackages, jq, lib, makeWrapper, stdenv }:\nstdenv.mkDerivation {"
+            ));
+            assert!(out.contains("pname = \"X\";"));
+            assert!(out.contains("version = \"1.0\";"));
+            assert!(out.contains("nativeBuildInputs = [ makeWrapper jq ];"));
+            assert!(out.contains("--prefix PATH : ${lib.makeBinPath [ jq ]}"));
+            assert!(out.contains("mainProgram = \"X\";"));
+            assert!(!out.contains("pkgs"));
+            assert_no_errors(&out);
+        }
+
+        #[test]
+        fn embedded_script() {
+            let root = PathBuf::from("./.");
+            let path = PathBuf::from("X");
+            let mut derivation = Derivation::new(&root, &path, "mv $SRC $DEST", None).unwrap();
+            derivation.embed_script("echo hi\n");
+            derivation.use_call_package("1.0");
+
+            let out = derivation.to_string();
+            assert!(out.contains("src = writeTextFile {"));
+            assert!(out.starts_with("{ lib, makeWrapper, stdenv, writeTextFile }:"));
+            assert_no_errors(&out);
+        }
+    }
+
+    mod tricky_names {
+        use super::*;
+
+        const NAMES: &[&str] = &[
+            "with space",
+            "quote\"d",
+            "dollar$HOME",
+            "${interpolation}",
+            "it's",
+            "two''quotes",
+            ".hidden",
+        ];
+
+        fn derivation(name: &str) -> Derivation {
+            let root = PathBuf::from(format!("/tmp/{name}"));
+            let path = PathBuf::from(name);
+            let mut derivation =
+                Derivation::new(&root, &path, "echo '${x}' ''; mv $SRC $OUT", None).unwrap();
+            derivation.set_interpreter("bash").unwrap();
+            derivation
+                .add_runtime_files(vec![(PathBuf::from(name), PathBuf::from(name))])
+                .unwrap();
+            derivation.add_runtime_inputs(vec![("jq").parse().unwrap()]);
+            derivation
+        }
+
+        #[test]
+        fn parse() {
+            for name in NAMES {
+                assert_no_errors(&derivation(name).to_string());
+            }
+        }
+
+        #[test]
+        fn sanitize_derivation_name() {
+            let out = derivation("with space").to_string();
+
+            assert!(out.contains("name = \"with-space\";"));
+            assert!(out.contains("OUT=bin/'with space'"));
+        }
+
+        #[test]
+        fn escape_build_command() {
+            let out = derivation("x").to_string();
+
+            assert!(out.contains("echo ${\"'\"}''${x}' '''; mv $SRC $OUT"));
+        }
+
+        #[test]
+        fn name_src_with_spaces() {
+            let out = derivation("with space").to_string();
+
+            assert!(out.contains(
+                "src = builtins.path {\n    name = \"source\";\n    path = (/. + \"/tmp/with space\");"
+            ));
+        }
+    }
+
+    mod runtime_files {
+        use super::*;
+
+        fn derivation() -> Derivation {
+            let root = PathBuf::from("/");
+            let path = PathBuf::from("X");
+            Derivation::new(&root, &path, "mv $SRC $DEST", None).unwrap()
+        }
+
+        fn same(path: &str) -> (PathBuf, PathBuf) {
+            (PathBuf::from(path), PathBuf::from(path))
+        }
+
+        #[test]
+        fn keeps_relative_paths() {
+            let mut derivation = derivation();
+            derivation
+                .add_runtime_files(vec![same("data/a.json"), same("conf/a.json")])
+                .unwrap();
+
+            let out = derivation.to_string();
+            assert!(out.contains("mkdir -p $out/usr/share/X/data\n"));
+            assert!(out.contains("cp -r data/a.json $out/usr/share/X/data/a.json\n"));
+            assert!(out.contains("cp -r conf/a.json $out/usr/share/X/conf/a.json\n"));
+            assert_no_errors(&out);
+        }
+
+        #[test]
+        fn renames() {
+            let mut derivation = derivation();
+            derivation
+                .add_runtime_files(vec![(
+                    PathBuf::from("config.prod.toml"),
+                    PathBuf::from("config.toml"),
+                )])
+                .unwrap();
+
+            assert!(derivation
+                .to_string()
+                .contains("cp -r config.prod.toml $out/usr/share/X/config.toml\n"));
+        }
+
+        #[test]
+        fn ignores_duplicates() {
+            let mut derivation = derivation();
+            derivation
+                .add_runtime_files(vec![same("a"), same("a")])
+                .unwrap();
+        }
+
+        #[test]
+        fn rejects_collisions() {
+            let mut derivation = derivation();
+            let problem = derivation
+                .add_runtime_files(vec![
+                    (PathBuf::from("a"), PathBuf::from("c")),
+                    (PathBuf::from("b"), PathBuf::from("c")),
+                ])
+                .unwrap_err();
+
+            assert_eq!(
+                "`a` and `b` would both be installed as runtime file `c`",
+                problem.to_string()
+            );
+        }
+
+        #[test]
+        fn rejects_overlaps() {
+            let mut derivation = derivation();
+            let problem = derivation
+                .add_runtime_files(vec![same("data"), same("data/a.json")])
+                .unwrap_err();
+
+            assert!(problem.to_string().contains("overlap"));
+        }
+
+        #[test]
+        fn rejects_escaping_destinations() {
+            let mut derivation = derivation();
+
+            assert!(derivation
+                .add_runtime_files(vec![(PathBuf::from("a"), PathBuf::from("../a"))])
+                .is_err());
+            assert!(derivation
+                .add_runtime_files(vec![(PathBuf::from("a"), PathBuf::from("/a"))])
+                .is_err());
+        }
+    }
+
+    mod embed_script {
+        use super::*;
+
+        #[test]
+        fn writes_script_into_src() {
+            let root = PathBuf::from("./.");
+            let path = PathBuf::from("hello.sh");
+            let mut derivation = Derivation::new(&root, &path, "mv $SRC $OUT", None).unwrap();
+            derivation.embed_script("#!/usr/bin/env bash\necho \"${1:-hi}\" ''\n");
+
+            let out = derivation.to_string();
+            assert!(out.contains(
+                "src = pkgs.writeTextFile {\n    name = \"hello.sh\";\n    destination = \"/hello.sh\";\n    text = \"#!/usr/bin/env bash\\necho \\\"\\${1:-hi}\\\" ''\\n\";\n  };"
+            ));
+            assert_no_errors(&out);
+        }
+
+        #[test]
+        fn keeps_script_exactly() {
+            let root = PathBuf::from("./.");
+            let path = PathBuf::from("X");
+            let mut derivation = Derivation::new(&root, &path, "mv $SRC $DEST", None).unwrap();
+            derivation.embed_script("    echo '${HOME}'\r\n    echo \"hi\"\r\n");
+
+            let out = derivation.to_string();
+            assert!(out.contains("text = \"    echo '\\${HOME}'\\r\\n    echo \\\"hi\\\"\\r\\n\";"));
+            assert_no_errors(&out);
+        }
+    }
+
+    mod only_include {
+        use super::*;
+
+        #[test]
+        fn includes_script_and_files() {
+            let root = PathBuf::from("/project");
+            let path = PathBuf::from("script.sh");
+            let mut derivation = Derivation::new(&root, &path, "mv $SRC $OUT", None).unwrap();
+            derivation.only_include(vec![PathBuf::from("data/a b.json")]);
+
+            let out = derivation.to_string();
+            assert!(out.contains("src = pkgs.lib.fileset.toSource {\n    root = /project;\n    fileset = pkgs.lib.fileset.unions [ (/. + \"/project/data/a b.json\") /project/script.sh ];"));
+            assert_no_errors(&out);
+        }
+
+        #[test]
+        fn whole_root_by_default() {
+            let root = PathBuf::from("/project");
+            let path = PathBuf::from("script.sh");
+            let derivation = Derivation::new(&root, &path, "mv $SRC $OUT", None).unwrap();
+
+            assert!(derivation.to_string().contains("src = /project;"));
+        }
+    }
+
+    mod project_files {
+        use super::*;
+
+        #[test]
+        fn shell() {
+            let root = PathBuf::from("./.");
+            let path = PathBuf::from("X");
+            let derivation = Derivation::new(&root, &path, "mv $SRC $OUT", None).unwrap();
+
+            let out = derivation.shell();
+            assert!(out.contains("pkgs.mkShell"));
+            assert_no_errors(&out);
+        }
+
+        #[test]
+        fn flake() {
+            let root = PathBuf::from("./.");
+            let path = PathBuf::from("X");
+            let config = "{ config.allowUnfree = true; }".parse().unwrap();
+            let derivation = Derivation::new(&root, &path, "mv $SRC $OUT", Some(&config)).unwrap();
+
+            let out = derivation.flake();
+            assert!(out.contains("inputs.nixpkgs.url = \"github:NixOS/nixpkgs/nixpkgs-unstable\";"));
+            assert!(out.contains(
+                "import nixpkgs ({ inherit system; } // { config.allowUnfree = true; })"
+            ));
+            assert_no_errors(&out);
+        }
+    }
+
+    mod env {
+        use super::*;
+
+        #[test]
+        fn with_env() {
+            let root = PathBuf::from("/");
+            let path = PathBuf::from("X");
+            let mut derivation = Derivation::new(&root, &path, "mv $SRC $DEST", None).unwrap();
+            derivation.add_env(
+                EnvVar::parse_line("A=\"it's ''${x}\" --prefix PYTHONPATH : ./lib").unwrap(),
+            );
+
+            let out = derivation.to_string();
+            assert!(out.contains("--prefix PYTHONPATH : ./lib"));
+            assert_no_errors(&out);
+        }
+
+        #[test]
+        fn with_wrapper_args() {
+            let root = PathBuf::from("/");
+            let path = PathBuf::from("X");
+            let mut derivation = Derivation::new(&root, &path, "mv $SRC $DEST", None).unwrap();
+            derivation.add_wrapper_args(
+                WrapperArg::parse_line("--chdir /tmp --run 'echo ${HOME}' --add-flags -v").unwrap(),
+            );
+
+            let out = derivation.to_string();
+            assert!(out.contains("--chdir /tmp \\\n"));
+            assert!(out.contains("--run 'echo ''${HOME}'"));
+            assert!(out.contains("--add-flags -v"));
+            assert_no_errors(&out);
+        }
+
+        #[test]
+        fn with_wrapper_flags_expanded_at_runtime() {
+            let root = PathBuf::from("/");
+            let path = PathBuf::from("X");
+            let mut derivation = Derivation::new(&root, &path, "mv $SRC $DEST", None).unwrap();
+            derivation.add_wrapper_args(
+                WrapperArg::parse_line("--add-flags '$HOME' --append-flags '\"$@\"'").unwrap(),
+            );
+
+            let out = derivation.to_string();
+            assert!(out.contains("--add-flags '$HOME' \\\n"));
+            assert!(out.contains("--append-flags '\"$@\"'"));
+            assert_no_errors(&out);
+        }
+
+        #[test]
+        fn with_wrapper_args_expanded_while_building() {
+            let root = PathBuf::from("/");
+            let path = PathBuf::from("X");
+            let mut derivation = Derivation::new(&root, &path, "mv $SRC $DEST", None).unwrap();
+            derivation.add_wrapper_args(
+                WrapperArg::parse_line(
+                    "--prefix LD_LIBRARY_PATH : /nix/store/abc-zlib/lib \
+                     --prefix LD_LIBRARY_PATH : '${lib.makeLibraryPath [ zlib ]}' \
+                     --set DATA '$out/share/\"data\"'",
+                )
+                .unwrap(),
+            );
+
+            let out = derivation.to_string();
+            assert!(out.contains("--prefix LD_LIBRARY_PATH : /nix/store/abc-zlib/lib \\\n"));
+            assert!(out.contains(
+                "--prefix LD_LIBRARY_PATH : \"${with pkgs; lib.makeLibraryPath [ zlib ]}\" \\\n"
+            ));
+            assert!(out.contains("--set DATA \"$out/share/\\\"data\\\"\""));
+            assert_no_errors(&out);
+        }
+
+        #[test]
+        fn with_build_env() {
+            let root = PathBuf::from("/");
+            let path = PathBuf::from("X");
+            let mut derivation = Derivation::new(&root, &path, "mv $SRC $DEST", None).unwrap();
+            derivation.add_build_env(EnvVar::parse_line("A=1 --suffix PATH : /opt/bin").unwrap());
+
+            let out = derivation.to_string();
+            assert!(out.contains("export A=1"));
+            assert!(out.contains("export PATH=''${PATH:+\"$PATH\":}/opt/bin"));
+            assert_no_errors(&out);
+        }
+    }
+
+    mod escape_sed {
+        use super::*;
+
+        #[test]
+        fn leaves_plain_characters() {
+            assert_eq!("#!", escape_sed("#!"))
+        }
+
+        #[test]
+        fn escapes_special_characters() {
+            assert_eq!("\\/\\/", escape_sed("//"));
+            assert_eq!("\\$\\*", escape_sed("$*"));
+        }
     }
 }
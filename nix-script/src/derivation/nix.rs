@@ -0,0 +1,597 @@
+use nix_script_directives::expr::Expr;
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
+
+/// A small Nix expression builder. Everything that comes from outside
+/// (script names, paths, build commands) goes in as data and gets escaped
+/// when rendered, so it can't change the shape of the expression around it.
+#[derive(Debug)]
+pub enum Nix {
+    /// Nix source we trust to be well-formed already, like identifiers we
+    /// chose or expressions we parsed from directives.
+    Raw(String),
+    Str(String),
+    Indented(Indented),
+    Path(PathBuf),
+    List(Vec<Nix>),
+    Attrs(Vec<(String, Nix)>),
+    Apply(Box<Nix>, Box<Nix>),
+    With(Box<Nix>, Box<Nix>),
+    Lambda(String, Box<Nix>),
+    Let(Vec<(String, Nix)>, Box<Nix>),
+}
+
+impl Nix {
+    pub fn raw(raw: impl Into<String>) -> Self {
+        Nix::Raw(raw.into())
+    }
+
+    pub fn str(text: impl Into<String>) -> Self {
+        Nix::Str(text.into())
+    }
+
+    /// An expression parsed from a directive, parenthesized if it's not a
+    /// single identifier so it can be used as a list item.
+    pub fn expr(expr: &Expr) -> Self {
+        if expr.is_leaf() {
+            Nix::Raw(expr.to_string())
+        } else {
+            Nix::Raw(format!("({expr})"))
+        }
+    }
+
+    pub fn apply(func: Nix, arg: Nix) -> Self {
+        Nix::Apply(Box::new(func), Box::new(arg))
+    }
+
+    pub fn with(scope: Nix, body: Nix) -> Self {
+        Nix::With(Box::new(scope), Box::new(body))
+    }
+
+    pub fn lambda(args: impl Into<String>, body: Nix) -> Self {
+        Nix::Lambda(args.into(), Box::new(body))
+    }
+
+    pub fn let_in(bindings: Vec<(String, Nix)>, body: Nix) -> Self {
+        Nix::Let(bindings, Box::new(body))
+    }
+
+    fn render(&self, out: &mut String, indent: usize) {
+        match self {
+            Nix::Raw(raw) => out.push_str(raw),
+            Nix::Str(text) => {
+                out.push('"');
+                out.push_str(&escape_string(text));
+                out.push('"');
+            }
+            Nix::Indented(indented) => indented.render(out, indent),
+            Nix::Path(path) => out.push_str(&render_path(path)),
+            Nix::List(items) => {
+                out.push('[');
+                for item in items {
+                    out.push(' ');
+                    item.render_arg(out, indent);
+                }
+                out.push_str(" ]");
+            }
+            Nix::Attrs(attrs) => {
+                if attrs.is_empty() {
+                    out.push_str("{ }");
+                    return;
+                }
+
+                out.push_str("{\n");
+                for (name, value) in attrs {
+                    push_indent(out, indent + 2);
+                    out.push_str(&attr_name(name));
+                    out.push_str(" = ");
+                    value.render(out, indent + 2);
+                    out.push_str(";\n");
+                }
+                push_indent(out, indent);
+                out.push('}');
+            }
+            Nix::Apply(func, arg) => {
+                func.render(out, indent);
+                out.push(' ');
+                arg.render_arg(out, indent);
+            }
+            Nix::With(scope, body) => {
+                out.push_str("with ");
+                scope.render(out, indent);
+                out.push_str("; ");
+                body.render(out, indent);
+            }
+            Nix::Lambda(args, body) => {
+                out.push_str(args);
+                out.push_str(":\n");
+                push_indent(out, indent);
+                body.render(out, indent);
+            }
+            Nix::Let(bindings, body) => {
+                out.push_str("let\n");
+                for (name, value) in bindings {
+                    push_indent(out, indent + 2);
+                    out.push_str(&attr_name(name));
+                    out.push_str(" = ");
+                    value.render(out, indent + 2);
+                    out.push_str(";\n");
+                }
+                push_indent(out, indent);
+                out.push_str("in\n");
+                push_indent(out, indent);
+                body.render(out, indent);
+            }
+        }
+    }
+
+    /// Render in a position where function application would bind tighter
+    /// than this expression (function arguments and list items.)
+    fn render_arg(&self, out: &mut String, indent: usize) {
+        match self {
+            Nix::Apply(..) | Nix::With(..) | Nix::Lambda(..) | Nix::Let(..) => {
+                out.push('(');
+                self.render(out, indent);
+                out.push(')');
+            }
+            _ => self.render(out, indent),
+        }
+    }
+}
+
+impl Display for Nix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let mut out = String::new();
+        self.render(&mut out, 0);
+        f.write_str(&out)
+    }
+}
+
+/// An indented string (`'' ... ''`), built up from literal text and
+/// interpolated expressions. Nix strips the indentation the lines have in
+/// common, so this is for shell code; use `Nix::str` for text that has to
+/// stay exactly as it is.
+#[derive(Debug, Default)]
+pub struct Indented(Vec<Part>);
+
+#[derive(Debug)]
+enum Part {
+    Text(String),
+    Interpolation(Nix),
+}
+
+impl Indented {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_str(&mut self, text: &str) {
+        match self.0.last_mut() {
+            Some(Part::Text(existing)) => existing.push_str(text),
+            _ => self.0.push(Part::Text(text.to_owned())),
+        }
+    }
+
+    pub fn push_line(&mut self, text: &str) {
+        self.push_str(text);
+        self.push_str("\n");
+    }
+
+    pub fn interpolate(&mut self, expr: Nix) {
+        self.0.push(Part::Interpolation(expr))
+    }
+
+    fn render(&self, out: &mut String, indent: usize) {
+        let mut body = String::new();
+        for part in &self.0 {
+            match part {
+                Part::Text(text) => body.push_str(&escape_indented(text)),
+                Part::Interpolation(expr) => {
+                    body.push_str("${");
+                    expr.render(&mut body, 0);
+                    body.push('}');
+                }
+            }
+        }
+
+        out.push_str("''\n");
+        for line in body.lines() {
+            if !line.is_empty() {
+                push_indent(out, indent + 2);
+                out.push_str(line);
+            }
+            out.push('\n');
+        }
+        push_indent(out, indent);
+        out.push_str("''");
+    }
+}
+
+/// Turn a file name into something Nix will accept as the name of a store
+/// path. Characters outside the allowed set become `-`.
+pub fn store_name(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "+-._?=".contains(c) {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+
+    match cleaned.trim_start_matches('.') {
+        "" => "script".to_owned(),
+        trimmed => trimmed.to_owned(),
+    }
+}
+
+fn push_indent(out: &mut String, indent: usize) {
+    out.extend(std::iter::repeat_n(' ', indent));
+}
+
+/// Attribute names that are identifiers (or paths of identifiers, like
+/// `inputs.nixpkgs.url`) stay as they are. Others are quoted.
+fn attr_name(name: &str) -> String {
+    let is_identifier = |segment: &str| {
+        let mut chars = segment.chars();
+        chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || "_'-".contains(c))
+    };
+
+    if name.split('.').all(is_identifier) {
+        name.to_owned()
+    } else {
+        format!("\"{}\"", escape_string(name))
+    }
+}
+
+/// Paths made of plain characters can be written as literals. Anything else
+/// is built by appending a string to the root (or current) directory.
+fn render_path(path: &Path) -> String {
+    let text = path.to_string_lossy();
+    let (base, rest) = match text.strip_prefix('/') {
+        Some(rest) => ("/", rest),
+        None => ("./", text.trim_start_matches("./")),
+    };
+
+    let rest = rest.trim_end_matches("/.");
+    if rest.is_empty() || rest == "." {
+        return format!("{base}.");
+    }
+
+    let is_plain = rest.split('/').all(|segment| {
+        !segment.is_empty()
+            && segment
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "._+-".contains(c))
+    });
+
+    if is_plain {
+        format!("{base}{rest}")
+    } else {
+        format!("({base}. + \"/{}\")", escape_string(rest))
+    }
+}
+
+/// Escape text for use inside a Nix string (`" ... "`).
+fn escape_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '$' if chars.peek() == Some(&'{') => out.push_str("\\$"),
+            other => out.push(other),
+        }
+    }
+
+    out
+}
+
+/// Escape text for use inside a Nix indented string (`'' ... ''`), where `''`
+/// ends the string and `${` starts an interpolation.
+fn escape_indented(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                let mut quotes = 1;
+                while chars.peek() == Some(&'\'') {
+                    chars.next();
+                    quotes += 1;
+                }
+
+                for _ in 0..quotes / 2 {
+                    out.push_str("'''");
+                }
+
+                // A lone quote right before the end of the string or an
+                // escaped `${` would run into the quotes that follow, so we
+                // interpolate it instead.
+                if quotes % 2 == 1 {
+                    let mut rest = chars.clone();
+                    match (rest.next(), rest.next()) {
+                        (None, _) | (Some('$'), Some('{')) => out.push_str("${\"'\"}"),
+                        _ => out.push('\''),
+                    }
+                }
+            }
+            '$' if chars.peek() == Some(&'{') => out.push_str("''$"),
+            '\r' => out.push_str("''\\r"),
+            other => out.push(other),
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::derivation::tests::assert_no_errors;
+
+    /// Parse a rendered string and get its value back. We only ever
+    /// interpolate string literals in these tests, so we can read those
+    /// directly.
+    fn round_trip(nix: &Nix) -> String {
+        let src = nix.to_string();
+        assert_no_errors(&src);
+
+        let string = match rnix::Root::parse(&src).tree().expr().unwrap() {
+            rnix::ast::Expr::Str(string) => string,
+            other => panic!("expected a string, got {other:?}"),
+        };
+
+        string
+            .normalized_parts()
+            .into_iter()
+            .map(|part| match part {
+                rnix::ast::InterpolPart::Literal(literal) => literal,
+                rnix::ast::InterpolPart::Interpolation(interpolation) => {
+                    match interpolation.expr().unwrap() {
+                        rnix::ast::Expr::Str(inner) => inner
+                            .normalized_parts()
+                            .into_iter()
+                            .map(|part| match part {
+                                rnix::ast::InterpolPart::Literal(literal) => literal,
+                                _ => panic!("nested interpolation"),
+                            })
+                            .collect(),
+                        other => format!("${{{other}}}"),
+                    }
+                }
+            })
+            .collect()
+    }
+
+    fn indented(text: &str) -> Nix {
+        let mut indented = Indented::new();
+        indented.push_str(text);
+        Nix::Indented(indented)
+    }
+
+    const TRICKY: &[&str] = &[
+        "plain",
+        "with space",
+        "quote\"d",
+        "dollar $HOME",
+        "${interpolation}",
+        "\\${escaped}",
+        "back\\slash",
+        "'",
+        "''",
+        "'''",
+        "a''b",
+        "'${x}",
+        "''${x}",
+        "x'",
+        "'x'",
+        "tab\there",
+    ];
+
+    mod str {
+        use super::*;
+
+        #[test]
+        fn round_trips() {
+            for text in TRICKY {
+                assert_eq!(*text, round_trip(&Nix::str(*text)))
+            }
+        }
+
+        #[test]
+        fn round_trips_newlines() {
+            assert_eq!("a\nb", round_trip(&Nix::str("a\nb")))
+        }
+
+        #[test]
+        fn round_trips_indentation_and_crlf() {
+            let text = "  indented\r\n    more\r\n";
+            assert_eq!(text, round_trip(&Nix::str(text)))
+        }
+    }
+
+    mod indented {
+        use super::*;
+
+        #[test]
+        fn round_trips() {
+            for text in TRICKY {
+                assert_eq!(format!("{text}\n"), round_trip(&indented(text)))
+            }
+        }
+
+        #[test]
+        fn round_trips_crlf() {
+            assert_eq!("a\r\nb\r\n", round_trip(&indented("a\r\nb\r\n")))
+        }
+
+        #[test]
+        fn round_trips_lines() {
+            let text = "if true; then\n  echo ''\nfi";
+            assert_eq!(format!("{text}\n"), round_trip(&indented(text)))
+        }
+
+        #[test]
+        fn interpolates() {
+            let mut indented = Indented::new();
+            indented.push_str("source ");
+            indented.interpolate(Nix::raw("makeWrapper"));
+            indented.push_line("/nix-support/setup-hook");
+
+            assert_eq!(
+                "source ${makeWrapper}/nix-support/setup-hook\n",
+                round_trip(&Nix::Indented(indented))
+            )
+        }
+
+        #[test]
+        fn quote_before_interpolation() {
+            let mut indented = Indented::new();
+            indented.push_str("it'");
+            indented.interpolate(Nix::raw("x"));
+
+            assert_eq!("it'${x}\n", round_trip(&Nix::Indented(indented)))
+        }
+    }
+
+    mod path {
+        use super::*;
+
+        #[test]
+        fn root() {
+            assert_eq!("/.", Nix::Path("/".into()).to_string())
+        }
+
+        #[test]
+        fn plain() {
+            assert_eq!("/a/b-c.d", Nix::Path("/a/b-c.d".into()).to_string())
+        }
+
+        #[test]
+        fn tricky() {
+            for text in TRICKY {
+                let nix = Nix::Path(PathBuf::from(format!("/tmp/{text}")));
+                assert_no_errors(&nix.to_string());
+            }
+        }
+
+        #[test]
+        fn spaces() {
+            assert_eq!("(/. + \"/a b\")", Nix::Path("/a b".into()).to_string())
+        }
+
+        #[test]
+        fn relative() {
+            assert_eq!("./a", Nix::Path("a".into()).to_string());
+            assert_eq!("(./. + \"/a b\")", Nix::Path("a b".into()).to_string());
+        }
+
+        #[test]
+        fn current() {
+            assert_eq!("./.", Nix::Path("./.".into()).to_string());
+            assert_eq!("./.", Nix::Path(".".into()).to_string());
+        }
+    }
+
+    mod attrs {
+        use super::*;
+
+        #[test]
+        fn empty() {
+            assert_eq!("{ }", Nix::Attrs(Vec::new()).to_string())
+        }
+
+        #[test]
+        fn quotes_names() {
+            let nix = Nix::Attrs(vec![
+                ("name".into(), Nix::str("x")),
+                ("a b".into(), Nix::str("y")),
+            ]);
+
+            assert_eq!("{\n  name = \"x\";\n  \"a b\" = \"y\";\n}", nix.to_string())
+        }
+
+        #[test]
+        fn keeps_attribute_paths() {
+            let nix = Nix::Attrs(vec![("a.b".into(), Nix::str("x"))]);
+
+            assert_eq!("{\n  a.b = \"x\";\n}", nix.to_string())
+        }
+
+        #[test]
+        fn nests_indented_strings() {
+            let nix = Nix::apply(
+                Nix::raw("f"),
+                Nix::Attrs(vec![("phase".into(), indented("echo hi"))]),
+            );
+
+            assert_no_errors(&nix.to_string());
+            assert_eq!("f {\n  phase = ''\n    echo hi\n  '';\n}", nix.to_string())
+        }
+    }
+
+    mod let_in {
+        use super::*;
+
+        #[test]
+        fn renders_bindings() {
+            let nix = Nix::let_in(
+                vec![("x".into(), Nix::str("a"))],
+                Nix::lambda("y", Nix::raw("x")),
+            );
+
+            assert_no_errors(&nix.to_string());
+            assert_eq!("let\n  x = \"a\";\nin\ny:\nx", nix.to_string())
+        }
+    }
+
+    mod apply {
+        use super::*;
+
+        #[test]
+        fn parenthesizes_arguments() {
+            let nix = Nix::apply(
+                Nix::raw("f"),
+                Nix::apply(Nix::raw("g"), Nix::List(vec![Nix::raw("x")])),
+            );
+
+            assert_eq!("f (g [ x ])", nix.to_string())
+        }
+    }
+
+    mod store_name {
+        use super::*;
+
+        #[test]
+        fn keeps_valid_names() {
+            assert_eq!("my-script.sh", store_name("my-script.sh"))
+        }
+
+        #[test]
+        fn replaces_invalid_characters() {
+            assert_eq!("my-cool-script-", store_name("my cool script$"))
+        }
+
+        #[test]
+        fn strips_leading_dots() {
+            assert_eq!("hidden", store_name(".hidden"))
+        }
+
+        #[test]
+        fn never_empty() {
+            assert_eq!("script", store_name("..."))
+        }
+    }
+}
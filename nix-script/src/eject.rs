@@ -0,0 +1,196 @@
+use crate::builder::Builder;
+use anyhow::{Context, Result};
+use nix_script_directives::Directives;
+use std::fs;
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// Turn a script into a standalone project in `to`: the script and the files
+/// it needs, a `default.nix` with all directives resolved, a `shell.nix`, and
+/// (if asked) a `flake.nix`. Returns a builder for the new project.
+pub fn eject(
+    builder: &Builder,
+    directives: &Directives,
+    to: &Path,
+    flake: bool,
+) -> Result<Builder> {
+    if builder.has_default_nix() {
+        anyhow::bail!("the build root already has a default.nix, so there is nothing to eject")
+    }
+
+    if builder.includes_whole_root() {
+        anyhow::bail!("cannot eject with --whole-build-root; list the files the script needs with #!buildFiles instead")
+    }
+
+    if to.exists() {
+        let mut entries =
+            fs::read_dir(to).with_context(|| format!("could not read {}", to.display()))?;
+
+        if entries.next().is_some() {
+            anyhow::bail!("{} is not empty; I won't overwrite anything", to.display())
+        }
+    } else {
+        fs::create_dir_all(to).with_context(|| format!("could not create {}", to.display()))?;
+    }
+
+    let from = builder.files_root()?;
+    for file in builder
+        .source_files(directives)
+        .context("could not find the files the script needs")?
+    {
+        log::debug!("copying {}", file.display());
+        copy(&from.join(&file), &to.join(&file))
+            .with_context(|| format!("could not copy {}", file.display()))?;
+    }
+
+    let ejected = builder
+        .relocated(to)
+        .context("could not set up a builder for the ejected project")?;
+    let derivation = ejected
+        .derivation(directives, true)
+        .context("could not create a Nix derivation from the script")?;
+
+    log::debug!("writing default.nix and shell.nix");
+    fs::write(to.join("default.nix"), format!("{derivation}\n"))
+        .context("could not write default.nix")?;
+    fs::write(to.join("shell.nix"), derivation.shell()).context("could not write shell.nix")?;
+
+    if flake {
+        log::debug!("writing flake.nix");
+        fs::write(to.join("flake.nix"), derivation.flake()).context("could not write flake.nix")?;
+    }
+
+    Ok(ejected)
+}
+
+/// Build the original script and the ejected project, and make sure they end
+/// up at the same store path.
+pub fn verify(
+    original: &mut Builder,
+    ejected: &mut Builder,
+    directives: &Directives,
+    cache_directory: &Path,
+) -> Result<()> {
+    log::info!("building the original script");
+    let hash = original
+        .hash(directives)
+        .context("could not hash the original script")?;
+    let expected = original
+        .build(cache_directory, &hash, directives, false)
+        .context("could not build the original script")?;
+
+    log::info!("building the ejected project");
+    let hash = ejected
+        .hash(directives)
+        .context("could not hash the ejected project")?;
+    let actual = ejected
+        .build(cache_directory, &hash, directives, false)
+        .context("could not build the ejected project")?;
+
+    if expected != actual {
+        anyhow::bail!(
+            "the ejected project builds to {}, but the script builds to {}",
+            actual.display(),
+            expected.display()
+        )
+    }
+
+    Ok(())
+}
+
+/// Copy a file, or a directory and everything in it, creating parent
+/// directories as needed.
+fn copy(from: &Path, to: &Path) -> Result<()> {
+    for entry in WalkDir::new(from).follow_links(true) {
+        let entry = entry.context("could not read directory entry")?;
+        let relative = entry
+            .path()
+            .strip_prefix(from)
+            .context("walked outside of the copied directory; this is a bug; please report")?;
+
+        // Joining an empty path would add a trailing slash, which makes
+        // copying a single file fail.
+        let dest = if relative.as_os_str().is_empty() {
+            to.to_owned()
+        } else {
+            to.join(relative)
+        };
+
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&dest)
+                .with_context(|| format!("could not create {}", dest.display()))?;
+        } else {
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("could not create {}", parent.display()))?;
+            }
+
+            fs::copy(entry.path(), &dest)
+                .with_context(|| format!("could not copy to {}", dest.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn directives(script: &Path) -> Directives {
+        Directives::from_file(Some("#!"), script).unwrap()
+    }
+
+    mod eject {
+        use super::*;
+
+        #[test]
+        fn writes_project() {
+            let from = tempdir().unwrap();
+            let to = tempdir().unwrap();
+            let script = from.path().join("script.sh");
+            fs::write(&script, "#!build cp $SRC $OUT\n").unwrap();
+
+            let builder = Builder::from_script(&script);
+            eject(&builder, &directives(&script), to.path(), true).unwrap();
+
+            for file in ["script.sh", "default.nix", "shell.nix", "flake.nix"] {
+                assert!(to.path().join(file).exists(), "{file} was not written");
+            }
+        }
+
+        #[test]
+        fn copies_runtime_files() {
+            let from = tempdir().unwrap();
+            let to = tempdir().unwrap();
+            let script = from.path().join("script.sh");
+            fs::write(&script, "#!build cp $SRC $OUT\n#!runtimeFiles data\n").unwrap();
+            fs::create_dir_all(from.path().join("data/nested")).unwrap();
+            fs::write(from.path().join("data/nested/file.txt"), "hi").unwrap();
+
+            let builder = Builder::from_directory(from.path(), &script).unwrap();
+            eject(&builder, &directives(&script), to.path(), false).unwrap();
+
+            assert_eq!(
+                "hi",
+                fs::read_to_string(to.path().join("data/nested/file.txt")).unwrap()
+            );
+            assert!(!to.path().join("flake.nix").exists());
+        }
+
+        #[test]
+        fn refuses_non_empty_target() {
+            let from = tempdir().unwrap();
+            let to = tempdir().unwrap();
+            let script = from.path().join("script.sh");
+            fs::write(&script, "#!build cp $SRC $OUT\n").unwrap();
+            fs::write(to.path().join("README"), "").unwrap();
+
+            let builder = Builder::from_script(&script);
+            let problem = eject(&builder, &directives(&script), to.path(), false).unwrap_err();
+
+            assert!(problem.to_string().contains("is not empty"));
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use anyhow::{Context, Result};
+use seahash::SeaHasher;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::hash::Hasher;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What we know about a file without reading it. If none of this changed,
+/// we assume the contents didn't either.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStat {
+    path: PathBuf,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    inode: u64,
+}
+
+impl FileStat {
+    pub fn new(path: &Path) -> Result<Self> {
+        let metadata =
+            fs::metadata(path).with_context(|| format!("could not stat {}", path.display()))?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            size: metadata.size(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            inode: metadata.ino(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    script: PathBuf,
+    settings: String,
+    files: Vec<FileStat>,
+    hash: String,
+}
+
+/// Remembers the hash we computed for a script, so we only have to `stat` its
+/// files next time instead of reading them. Each script gets one entry in its
+/// own file in the cache, so parallel runs don't step on each other and the
+/// index only grows with the number of scripts.
+#[derive(Debug)]
+pub struct Index {
+    root: PathBuf,
+}
+
+impl Index {
+    pub fn new(cache_directory: &Path) -> Self {
+        Self {
+            root: cache_directory.join(".index"),
+        }
+    }
+
+    /// The hash stored for `script`, if it was built with the same
+    /// `settings` and the files are exactly as they were when we stored it.
+    pub fn lookup(&self, script: &Path, settings: &str, files: &[FileStat]) -> Option<String> {
+        let raw = match fs::read(self.entry_path(script)) {
+            Ok(raw) => raw,
+            Err(err) => {
+                log::trace!("no index entry for {}: {err}", script.display());
+                return None;
+            }
+        };
+
+        match serde_json::from_slice::<Entry>(&raw) {
+            Ok(entry) if entry.script != script => {
+                log::debug!(
+                    "index entry for {} belongs to another script",
+                    script.display()
+                );
+                None
+            }
+            Ok(entry) if entry.settings == settings && entry.files == files => Some(entry.hash),
+            Ok(_) => {
+                log::debug!(
+                    "settings or files changed since the index entry for {} was written",
+                    script.display()
+                );
+                None
+            }
+            Err(err) => {
+                log::warn!(
+                    "ignoring unreadable index entry for {}: {err}",
+                    script.display()
+                );
+                None
+            }
+        }
+    }
+
+    /// Remember `hash` for `script`, replacing whatever we knew about it.
+    pub fn store(
+        &self,
+        script: &Path,
+        settings: &str,
+        files: Vec<FileStat>,
+        hash: &str,
+    ) -> Result<()> {
+        // A file changed in the same second as we stat it might change again
+        // without its mtime moving, so don't trust what we just saw.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("the system clock is set before 1970")?
+            .as_secs() as i64;
+        if files.iter().any(|file| file.mtime >= now - 1) {
+            log::debug!(
+                "not indexing {} because some files were modified just now",
+                script.display()
+            );
+            return Ok(());
+        }
+
+        fs::create_dir_all(&self.root).context("could not create index directory")?;
+
+        let entry = Entry {
+            script: script.to_owned(),
+            settings: settings.to_owned(),
+            files,
+            hash: hash.to_owned(),
+        };
+        let raw = serde_json::to_vec(&entry).context("could not serialize index entry")?;
+
+        // Write to a temporary file first, so readers never see half an entry.
+        let path = self.entry_path(script);
+        let mut temp = path.clone().into_os_string();
+        temp.push(format!(".{}", std::process::id()));
+        fs::write(&temp, raw).context("could not write index entry")?;
+        fs::rename(&temp, &path).context("could not move index entry in place")?;
+
+        Ok(())
+    }
+
+    fn entry_path(&self, script: &Path) -> PathBuf {
+        let mut hasher = SeaHasher::new();
+        hasher.write(script.as_os_str().as_bytes());
+
+        self.root.join(format!("{:x}", hasher.finish()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    // Make the file old enough to be indexed.
+    fn write_old(path: &Path, contents: &str) {
+        fs::write(path, contents).unwrap();
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(60))
+            .unwrap();
+    }
+
+    mod lookup {
+        use super::*;
+
+        #[test]
+        fn finds_unchanged_files() {
+            let cache = tempdir().unwrap();
+            let file = cache.path().join("script.sh");
+            write_old(&file, "echo hi");
+
+            let index = Index::new(cache.path());
+            index
+                .store(
+                    &file,
+                    "settings",
+                    vec![FileStat::new(&file).unwrap()],
+                    "abc",
+                )
+                .unwrap();
+
+            assert_eq!(
+                Some("abc".into()),
+                index.lookup(&file, "settings", &[FileStat::new(&file).unwrap()])
+            );
+        }
+
+        #[test]
+        fn misses_changed_files() {
+            let cache = tempdir().unwrap();
+            let file = cache.path().join("script.sh");
+            write_old(&file, "echo hi");
+
+            let index = Index::new(cache.path());
+            index
+                .store(
+                    &file,
+                    "settings",
+                    vec![FileStat::new(&file).unwrap()],
+                    "abc",
+                )
+                .unwrap();
+            write_old(&file, "echo bye");
+
+            assert_eq!(
+                None,
+                index.lookup(&file, "settings", &[FileStat::new(&file).unwrap()])
+            );
+        }
+
+        #[test]
+        fn misses_changed_settings() {
+            let cache = tempdir().unwrap();
+            let file = cache.path().join("script.sh");
+            write_old(&file, "echo hi");
+
+            let index = Index::new(cache.path());
+            index
+                .store(
+                    &file,
+                    "settings",
+                    vec![FileStat::new(&file).unwrap()],
+                    "abc",
+                )
+                .unwrap();
+
+            assert_eq!(
+                None,
+                index.lookup(&file, "other settings", &[FileStat::new(&file).unwrap()])
+            );
+        }
+
+        #[test]
+        fn misses_unknown_scripts() {
+            let cache = tempdir().unwrap();
+
+            assert_eq!(
+                None,
+                Index::new(cache.path()).lookup(Path::new("/script.sh"), "settings", &[])
+            );
+        }
+    }
+
+    mod store {
+        use super::*;
+
+        #[test]
+        fn skips_fresh_files() {
+            let cache = tempdir().unwrap();
+            let file = cache.path().join("script.sh");
+            fs::write(&file, "echo hi").unwrap();
+
+            let index = Index::new(cache.path());
+            index
+                .store(
+                    &file,
+                    "settings",
+                    vec![FileStat::new(&file).unwrap()],
+                    "abc",
+                )
+                .unwrap();
+
+            assert_eq!(
+                None,
+                index.lookup(&file, "settings", &[FileStat::new(&file).unwrap()])
+            );
+        }
+
+        #[test]
+        fn replaces_the_entry_for_a_script() {
+            let cache = tempdir().unwrap();
+            let file = cache.path().join("script.sh");
+            write_old(&file, "echo hi");
+
+            let index = Index::new(cache.path());
+            for (settings, hash) in [("one", "abc"), ("two", "def")] {
+                index
+                    .store(&file, settings, vec![FileStat::new(&file).unwrap()], hash)
+                    .unwrap();
+            }
+
+            assert_eq!(
+                1,
+                fs::read_dir(cache.path().join(".index")).unwrap().count()
+            );
+            assert_eq!(
+                Some("def".into()),
+                index.lookup(&file, "two", &[FileStat::new(&file).unwrap()])
+            );
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use anyhow::{Context, Result};
+use seahash::SeaHasher;
+use std::fs::{self, Permissions};
+use std::hash::Hasher;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Scripts given with `-e` or on stdin don't have a file, so we give them one
+/// in the cache. The directory is named after the source, so the same source
+/// always ends up at the same path and everything after that (directives,
+/// hashing, caching) works like it does for any other script.
+pub fn write(cache_directory: &Path, name: &str, source: &str) -> Result<PathBuf> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        anyhow::bail!("`{}` is not a valid name for an inline script", name)
+    }
+
+    let mut hasher = SeaHasher::new();
+    hasher.write(source.as_bytes());
+
+    let dir = cache_directory
+        .join("inline")
+        .join(format!("{:x}", hasher.finish()));
+    let path = dir.join(name);
+    if path.exists() {
+        log::debug!("inline script already exists at {}", path.display());
+        return Ok(path);
+    }
+
+    fs::create_dir_all(&dir).context("could not create directory for inline script")?;
+
+    // Write to a temporary file first, so parallel runs never see half a
+    // script.
+    let temp = dir.join(format!(".{name}.{}", std::process::id()));
+    fs::write(&temp, source).context("could not write inline script")?;
+    fs::set_permissions(&temp, Permissions::from_mode(0o755))
+        .context("could not make inline script executable")?;
+    fs::rename(&temp, &path).context("could not move inline script in place")?;
+
+    log::debug!("wrote inline script to {}", path.display());
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    mod write {
+        use super::*;
+
+        #[test]
+        fn same_source_same_path() {
+            let cache = tempdir().unwrap();
+
+            let first = write(cache.path(), "script", "echo hi").unwrap();
+            let second = write(cache.path(), "script", "echo hi").unwrap();
+
+            assert_eq!(first, second);
+            assert_eq!("echo hi", fs::read_to_string(first).unwrap());
+        }
+
+        #[test]
+        fn different_source_different_path() {
+            let cache = tempdir().unwrap();
+
+            assert_ne!(
+                write(cache.path(), "script", "echo hi").unwrap(),
+                write(cache.path(), "script", "echo bye").unwrap()
+            );
+        }
+
+        #[test]
+        fn uses_the_name() {
+            let cache = tempdir().unwrap();
+
+            let path = write(cache.path(), "hello.hs", "main = pure ()").unwrap();
+
+            assert_eq!(Some("hello.hs".as_ref()), path.file_name());
+        }
+
+        #[test]
+        fn rejects_paths_as_names() {
+            let cache = tempdir().unwrap();
+
+            assert_eq!(
+                "`../script` is not a valid name for an inline script",
+                write(cache.path(), "../script", "echo hi")
+                    .unwrap_err()
+                    .to_string()
+            );
+        }
+    }
+}
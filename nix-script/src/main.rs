@@ -1,10 +1,19 @@
 mod builder;
 mod clean_path;
 mod derivation;
+mod eject;
+mod index;
+mod inline;
 mod opts;
+mod rewrite;
+mod sandbox;
+mod signals;
+mod timings;
+mod watch;
 
 use clap::Parser;
 use opts::Opts;
+use std::os::unix::process::ExitStatusExt;
 
 fn main() {
     env_logger::Builder::from_env("NIX_SCRIPT_LOG").init();
@@ -12,10 +21,15 @@ fn main() {
     let opts = Opts::parse();
     log::trace!("opts: {opts:?}");
 
-    match opts.run().map(|status| status.code()) {
+    // Like a shell, we report a child killed by a signal as 128 plus the
+    // signal number.
+    match opts
+        .run()
+        .map(|status| status.code().or(status.signal().map(|signal| 128 + signal)))
+    {
         Ok(Some(code)) => std::process::exit(code),
         Ok(None) => {
-            log::warn!("No exit code; was the script killed with a signal?");
+            log::warn!("No exit code or signal; was the script stopped?");
             std::process::exit(1)
         }
         Err(err) => {
@@ -1,32 +1,65 @@
 use crate::builder::Builder;
 use crate::clean_path::clean_path;
+use crate::eject;
+use crate::index::Index;
+use crate::inline;
+use crate::sandbox::Sandbox;
+use crate::signals::run_forwarding_signals;
+use crate::timings::Timings;
+use crate::watch::{self, Targets, Watch};
 
 use anyhow::{Context, Result};
 use clap::Parser;
 use fs2::FileExt;
+use nix_script_directives::env::EnvVar;
 use nix_script_directives::expr::Expr;
 use nix_script_directives::Directives;
 use std::env;
 use std::fs::{self, File};
-use std::io::ErrorKind;
+use std::io::{self, ErrorKind, Read, Write};
 use std::os::unix::fs::symlink;
-use std::os::unix::process::ExitStatusExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path::{Path, PathBuf};
-use std::process::{Command, ExitStatus};
+use std::process::{Child, Command, ExitStatus};
+use std::time::Duration;
 
 // TODO: Options for the rest of the directives.
 #[derive(Debug, Parser)]
-#[clap(version, trailing_var_arg = true)]
+#[clap(version, trailing_var_arg = true, subcommand_negates_reqs = true)]
 pub struct Opts {
-    /// What indicator do directives start with in the source file?
-    #[clap(long, default_value = "#!")]
-    indicator: String,
+    /// What indicator do directives start with in the source file? If not
+    /// given, we accept `#!` as well as the line comment of the script's
+    /// language followed by `nix-script:`, guessed from the file extension or
+    /// the `#!` line (e.g. `// nix-script:` for C, Rust and Go, `--
+    /// nix-script:` for Haskell and Lua, `# nix-script:` for Python.)
+    #[clap(long)]
+    indicator: Option<String>,
+
+    /// Blank out directive lines in the copy of the script we build, so the
+    /// compiler never sees them. Line numbers stay the same.
+    #[clap(long)]
+    strip_directives: bool,
 
     /// How should we build this script? (Will override any `#!build` line
     /// present in the script.)
     #[clap(long)]
     build_command: Option<String>,
 
+    /// How should we check the built script? (Will override any `#!check`
+    /// line present in the script.) The script is not cached if this fails.
+    #[clap(long)]
+    check_command: Option<String>,
+
+    /// Don't run the check command, even if the script specifies one.
+    #[clap(long, conflicts_with("check_command"))]
+    skip_checks: bool,
+
+    /// How should we install the built script into `$out`? (Will override
+    /// any `#!installCommand` line present in the script.) The binary must
+    /// end up in `$out/bin`, where we wrap it as usual.
+    #[clap(long)]
+    install_command: Option<String>,
+
     /// Add build inputs to those specified by the source directives.
     #[clap(long("build-input"))]
     build_inputs: Vec<String>,
@@ -41,6 +74,22 @@ pub struct Opts {
     #[clap(long("runtime-input"))]
     runtime_inputs: Vec<String>,
 
+    /// Set an environment variable when running the script, as `KEY=VALUE`,
+    /// `--prefix KEY SEP VALUE` or `--suffix KEY SEP VALUE`. Adds to any `#!env`
+    /// directives in the script.
+    #[clap(long("env"), allow_hyphen_values = true)]
+    env: Vec<String>,
+
+    /// Set an environment variable while building the script. Takes the same
+    /// forms as `--env` and adds to any `#!buildEnv` directives in the script.
+    #[clap(long("build-env"), allow_hyphen_values = true)]
+    build_env: Vec<String>,
+
+    /// Pass extra flags to `makeWrapper`, like `--chdir DIR`, `--unset VAR`
+    /// or `--add-flags FLAGS`. Adds to any `#!wrapperArgs` directives.
+    #[clap(long("wrapper-arg"), allow_hyphen_values = true)]
+    wrapper_args: Vec<String>,
+
     /// Override the configuration that will be passed to nixpkgs on import.
     #[clap(
         long("nixpkgs-config"),
@@ -59,6 +108,16 @@ pub struct Opts {
     #[clap(long("export"), conflicts_with_all(&["parse", "shell"]))]
     export: bool,
 
+    /// With `--export`, which kind of derivation to print: one that imports
+    /// nixpkgs itself, or a package for `callPackage` that follows nixpkgs
+    /// conventions.
+    #[clap(long, value_enum, default_value_t = ExportFormat::Standalone, requires("export"))]
+    export_format: ExportFormat,
+
+    /// With `--export-format callpackage`, the version of the package.
+    #[clap(long, default_value = "0.1.0", requires("export"))]
+    export_version: String,
+
     /// Enter a shell with build-time and runtime inputs available.
     #[clap(long, conflicts_with_all(&["parse", "export"]))]
     shell: bool,
@@ -72,9 +131,11 @@ pub struct Opts {
     #[clap(long, requires("shell"))]
     pure: bool,
 
-    /// Use this folder as the root for any building we do. You can use this
-    /// to bring other files into scope in your build. If there is a `default.nix`
-    /// file in the specified root, we will use that instead of generating our own.
+    /// Use this folder as the root for any building we do. Runtime and build
+    /// files are relative to it, and only those files (plus the script) are
+    /// copied into the build unless `--whole-build-root` is given. If there
+    /// is a `default.nix` file in the specified root, we will use that
+    /// instead of generating our own.
     #[clap(long)]
     build_root: Option<PathBuf>,
 
@@ -82,22 +143,174 @@ pub struct Opts {
     #[clap(long)]
     runtime_files: Vec<PathBuf>,
 
+    /// Wrap these extra executables (which the build must put in `bin`)
+    /// alongside the script.
+    #[clap(long("binary"))]
+    binaries: Vec<String>,
+
+    /// Run this binary from the build instead of the script itself. It must
+    /// be listed in `#!binaries` or `--binary`.
+    #[clap(long, conflicts_with_all(&["parse", "export", "shell"]))]
+    exec: Option<String>,
+
+    /// Include files that are only needed while building (relative to the
+    /// build root.)
+    #[clap(long("build-file"))]
+    build_files: Vec<PathBuf>,
+
+    /// Copy the whole build root into the Nix store when building, instead
+    /// of only the script, runtime files and build files.
+    #[clap(long)]
+    whole_build_root: bool,
+
+    /// Run the script with bubblewrap (Linux only), so it can only read the
+    /// Nix store paths it depends on and whatever `#!allowRead`,
+    /// `#!allowWrite`, `#!allowNetwork` and `#!allowEnv` let in.
+    #[clap(
+        long,
+        env("NIX_SCRIPT_SANDBOX"),
+        value_parser = clap::builder::FalseyValueParser::new(),
+        conflicts_with_all(&["parse", "export", "shell"])
+    )]
+    sandbox: bool,
+
+    /// In the sandbox, let the script read this path. Adds to any
+    /// `#!allowRead` directives.
+    #[clap(long("allow-read"))]
+    allow_read: Vec<PathBuf>,
+
+    /// In the sandbox, let the script read and write this path. Adds to any
+    /// `#!allowWrite` directives.
+    #[clap(long("allow-write"))]
+    allow_write: Vec<PathBuf>,
+
+    /// In the sandbox, let the script use the network.
+    #[clap(long)]
+    allow_network: bool,
+
+    /// In the sandbox, pass this environment variable on to the script. Adds
+    /// to any `#!allowEnv` directives.
+    #[clap(long("allow-env"))]
+    allow_env: Vec<String>,
+
+    /// Keep running: build and run the script, then rebuild and rerun it
+    /// (with the same arguments) whenever the script, the files it includes
+    /// or its build root change. Files ignored by `.gitignore` or `.ignore`
+    /// in the build root don't count.
+    #[clap(long, conflicts_with_all(&["parse", "export", "shell"]))]
+    watch: bool,
+
+    /// Print how long each stage of the run took to stderr.
+    #[clap(
+        long,
+        env("NIX_SCRIPT_TIMINGS"),
+        value_parser = clap::builder::FalseyValueParser::new()
+    )]
+    timings: bool,
+
+    /// Append the timings of each run as a line of JSON to this file instead
+    /// of printing them. Implies `--timings`.
+    #[clap(long, value_name = "FILE")]
+    timings_file: Option<PathBuf>,
+
+    /// Only show the output of nix-build if the build fails. Either way,
+    /// `nix-script logs` shows it afterwards.
+    #[clap(long)]
+    quiet_build: bool,
+
+    /// Keep the generated `default.nix`, the isolated source and, if the
+    /// build fails, Nix's build tree around after building, and print where
+    /// they are.
+    #[clap(long)]
+    keep_build_dir: bool,
+
+    /// Print the exact derivation we build to stderr.
+    #[clap(long)]
+    print_derivation: bool,
+
     /// Where should we cache files?
     #[clap(long("cache-directory"), env("NIX_SCRIPT_CACHE"))]
     cache_directory: Option<PathBuf>,
 
-    /// The script to run (required), plus any arguments (optional). Any positional
-    /// arguments after the script name will be passed on to the script.
+    /// Run this source instead of a script file. All positional arguments
+    /// are then passed on to the script.
+    #[clap(short('e'), long, value_name = "SOURCE", allow_hyphen_values = true)]
+    eval: Option<String>,
+
+    /// The file name to give a script from `-e` or stdin. The extension is
+    /// used to guess the directive indicator, like for any other script.
+    #[clap(long, value_name = "NAME", default_value = "script")]
+    inline_name: String,
+
+    /// The script to run (required unless `-e` is given; use `-` to read it
+    /// from stdin), plus any arguments (optional). Any positional arguments
+    /// after the script name will be passed on to the script.
     // Note: it'd be better to have a "script" and "args" field separately,
     // but there's a parsing issue in Clap (not a bug, but maybe a bug?) that
     // prevents passing args starting in -- after the script if we do that. See
     // https://github.com/clap-rs/clap/issues/1538
-    #[clap(num_args = 1.., required = true)]
+    #[clap(num_args = 1.., required_unless_present("eval"))]
     script_and_args: Vec<String>,
+
+    #[clap(subcommand)]
+    command: Option<Subcommand>,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum ExportFormat {
+    /// A derivation that imports nixpkgs itself, for `nix-build`.
+    Standalone,
+    /// A package to build with `callPackage`, like the ones in nixpkgs.
+    #[value(name = "callpackage")]
+    CallPackage,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Subcommand {
+    /// Turn a script into a standalone Nix project: the script and the files
+    /// it needs, plus a `default.nix` and `shell.nix` generated from its
+    /// directives. Options given before `eject` are applied as usual.
+    Eject {
+        /// The script to eject.
+        script: PathBuf,
+
+        /// Where to put the project. Must be empty or not exist yet.
+        #[clap(long)]
+        to: PathBuf,
+
+        /// Also write a `flake.nix` exposing the package and the shell.
+        #[clap(long)]
+        flake: bool,
+
+        /// Don't build the script and the ejected project to check that they
+        /// produce the same output.
+        #[clap(long)]
+        no_verify: bool,
+    },
+
+    /// Show the log of the last build of the script as it is now, or the
+    /// newest log of any version if this one hasn't been built. Options
+    /// given before `logs` are applied as usual, since they change which
+    /// build that is.
+    Logs {
+        /// The script to show the build log for.
+        script: PathBuf,
+    },
 }
 
 impl Opts {
     pub fn run(&self) -> Result<ExitStatus> {
+        match &self.command {
+            Some(Subcommand::Eject {
+                script,
+                to,
+                flake,
+                no_verify,
+            }) => return self.eject(script, to, *flake, *no_verify),
+            Some(Subcommand::Logs { script }) => return self.logs(script),
+            None => {}
+        }
+
         // First things first: what are we running? Where does it live? What
         // are its arguments?
         let (mut script, args) = self
@@ -115,45 +328,8 @@ impl Opts {
             .to_str()
             .context("filename was not valid UTF-8")?;
 
-        // Parse our directives, but don't combine them with command-line arguments yet!
-        let mut directives = Directives::from_file(&self.indicator, &script)
-            .context("could not parse directives from script")?;
-
-        let mut build_root = self.build_root.to_owned();
-        if build_root.is_none() {
-            if let Some(from_directives) = &directives.build_root {
-                let out = script
-                    .parent()
-                    .map(Path::to_path_buf)
-                    .unwrap_or_else(|| PathBuf::from("."));
-
-                out.join(from_directives)
-                    .canonicalize()
-                    .context("could not canonicalize final path to build root")?;
-
-                log::debug!("path to root from script directive: {}", out.display());
-
-                build_root = Some(out);
-            }
-        };
-        if build_root.is_none()
-            && (!self.runtime_files.is_empty() || !directives.runtime_files.is_empty())
-        {
-            log::warn!("Requested runtime files without specifying a build root. I am assuming it is the parent directory of the script for now, but you should set it explicitly!");
-            build_root = Some(
-                script
-                    .parent()
-                    .map(|p| p.to_owned())
-                    .unwrap_or_else(|| PathBuf::from(".")),
-            );
-        }
-
-        let mut builder = if let Some(build_root) = &build_root {
-            Builder::from_directory(build_root, &script)
-                .context("could not initialize source in directory")?
-        } else {
-            Builder::from_script(&script)
-        };
+        let mut timings = Timings::new(self.timings || self.timings_file.is_some());
+        let (builder, mut directives) = self.load(&script, &mut timings)?;
 
         // First place we might bail early: if a script just wants to parse
         // directives using our parser, we dump JSON and quit instead of running.
@@ -169,22 +345,7 @@ impl Opts {
         // we shouldn't provide them in the output of `--parse` without showing
         // where each option came from. For now, we're assuming that people who
         // write wrapper scripts know what they want to pass into `nix-script`.
-        directives.maybe_override_build_command(&self.build_command);
-        directives
-            .merge_build_inputs(&self.build_inputs)
-            .context("could not add build inputs provided on the command line")?;
-        if let Some(interpreter) = &self.interpreter {
-            directives.override_interpreter(interpreter)
-        }
-        directives
-            .merge_runtime_inputs(&self.runtime_inputs)
-            .context("could not add runtime inputs provided on the command line")?;
-        directives.merge_runtime_files(&self.runtime_files);
-        if let Some(expr) = &self.nixpkgs_config {
-            directives
-                .override_nixpkgs_config(expr)
-                .context("could not set nixpkgs config provided on the command line")?;
-        }
+        self.merge_options(&mut directives)?;
 
         // Second place we might bail early: if we're requesting a shell instead
         // of building and running the script.
@@ -195,24 +356,21 @@ impl Opts {
         // Third place we can bail early: if someone wants the generated
         // derivation to do IFD or similar.
         if self.export {
-            // We check here instead of inside while isolating the script or
-            // similar so we can get an early bail that doesn't create trash
-            // in the system's temporary directories.
-            if build_root.is_none() {
-                anyhow::bail!(
-                    "I do not have a root to refer to while exporting, so I cannot isolate the script and dependencies. Specify a --build-root and try this again!"
-                )
+            let mut derivation = builder
+                .derivation(&directives, true)
+                .context("could not create a Nix derivation from the script")?;
+            if let ExportFormat::CallPackage = self.export_format {
+                derivation.use_call_package(&self.export_version);
             }
 
-            println!(
-                "{}",
-                builder
-                    .derivation(&directives, true)
-                    .context("could not create a Nix derivation from the script")?
-            );
+            println!("{derivation}");
             return Ok(ExitStatus::from_raw(0));
         }
 
+        if self.sandbox && !cfg!(target_os = "linux") {
+            anyhow::bail!("`--sandbox` is only supported on Linux")
+        }
+
         let cache_directory = self
             .get_cache_directory()
             .context("could not get cache directory")?;
@@ -221,10 +379,56 @@ impl Opts {
             cache_directory.display()
         );
 
+        if self.watch {
+            return self.watch(&script, &args, builder, directives, &cache_directory);
+        }
+
+        let binary = self.binary(script_name, &directives)?;
+        let target = self.build_cached(
+            builder,
+            &directives,
+            &cache_directory,
+            script_name,
+            &mut timings,
+        )?;
+
+        // Replace ourselves with the script, so it gets our PID and any
+        // signals sent to it. `exec` only returns if it failed.
+        let mut command = self.script_command(target, &binary, &script, &args, &directives)?;
+
+        // We can't measure the script itself, since we won't be around to
+        // see it finish, but we can measure everything up to starting it.
+        timings.finish("exec");
+        if let Err(err) = timings.report(&script, self.timings_file.as_deref()) {
+            log::warn!("could not report timings: {err:?}");
+        }
+
+        let err = command.exec();
+
+        if self.sandbox {
+            Err(err).context(
+                "could not run the script in the sandbox. Is bwrap (bubblewrap) on the PATH?",
+            )
+        } else {
+            Err(err).context("could not run the script")
+        }
+    }
+
+    /// Build the script unless the cache already has it, and return where
+    /// it is in the cache.
+    fn build_cached(
+        &self,
+        mut builder: Builder,
+        directives: &Directives,
+        cache_directory: &Path,
+        script_name: &str,
+        timings: &mut Timings,
+    ) -> Result<PathBuf> {
         // Create hash, check cache.
         let hash = builder
-            .hash(&directives)
+            .indexed_hash(directives, &Index::new(cache_directory))
             .context("could not calculate cache location for the compiled versoin of the script")?;
+        timings.finish("hash");
 
         let target_unique_id = format!("{hash}-{script_name}");
         let target = cache_directory.join(target_unique_id.clone());
@@ -265,10 +469,12 @@ impl Opts {
                 .lock_exclusive()
                 .context("could not obtain lock")?;
             log::debug!("obtained lock");
+            timings.finish("lock");
 
             let out_path = builder
-                .build(&cache_directory, &hash, &directives)
+                .build(cache_directory, &hash, directives, self.quiet_build)
                 .context("could not build derivation from script")?;
+            timings.finish("build");
 
             if let Err(err) = symlink(out_path, &target) {
                 match err.kind() {
@@ -294,29 +500,474 @@ impl Opts {
             // waiting for obtaining a lock on the file.
         } else {
             log::debug!("hashed path exists; skipping build");
+            if self.keep_build_dir || self.print_derivation {
+                log::warn!("The script is already built, so there is no build to keep or print. Change the script or clear the cache to build it again.");
+            }
+        }
+
+        Ok(target)
+    }
+
+    /// The command to run the built script (or one of its binaries) with,
+    /// in the sandbox if asked for.
+    fn script_command(
+        &self,
+        target: PathBuf,
+        binary: &str,
+        script: &Path,
+        args: &[String],
+        directives: &Directives,
+    ) -> Result<Command> {
+        // The cache isn't visible in the sandbox, so we go straight to the
+        // store path there.
+        let out_path = if self.sandbox {
+            target
+                .canonicalize()
+                .context("could not find the build output")?
+        } else {
+            target
+        };
+
+        let mut command = Command::new(out_path.join("bin").join(binary));
+        self.set_script_location(&mut command, script)?;
+        command.args(args);
+
+        if self.sandbox {
+            command = Sandbox::new(&out_path, &self.sandbox_base(script)?, directives)
+                .context("could not set up the sandbox")?
+                .wrap(&command);
+        }
+
+        Ok(command)
+    }
+
+    /// What relative paths in `allow*` directives are relative to: the
+    /// script's directory, or the current one for inline scripts, which only
+    /// live in the cache.
+    fn sandbox_base(&self, script: &Path) -> Result<PathBuf> {
+        if self.eval.is_some()
+            || self
+                .script_and_args
+                .first()
+                .is_some_and(|first| first == "-")
+        {
+            return env::current_dir().context("could not get the current directory");
+        }
+
+        Ok(script
+            .canonicalize()
+            .context("could not find the absolute path to the script")?
+            .parent()
+            .context("the script is not in a directory; this is a bug; please report")?
+            .to_owned())
+    }
+
+    /// Which binary of the build to run: the script itself unless `--exec`
+    /// says otherwise.
+    fn binary(&self, script_name: &str, directives: &Directives) -> Result<String> {
+        match &self.exec {
+            Some(binary) if binary != script_name && !directives.binaries.contains(binary) => {
+                anyhow::bail!(
+                    "`{}` is not one of this script's binaries (expected `{}` or one of {:?})",
+                    binary,
+                    script_name,
+                    directives.binaries
+                )
+            }
+            Some(binary) => Ok(binary.to_owned()),
+            None => Ok(script_name.to_owned()),
         }
+    }
+
+    /// Build and run the script, and do it again whenever the files it is
+    /// built from change. A run that is still going is stopped first, and
+    /// errors are printed instead of ending the loop.
+    fn watch(
+        &self,
+        script: &Path,
+        args: &[String],
+        builder: Builder,
+        directives: Directives,
+        cache_directory: &Path,
+    ) -> Result<ExitStatus> {
+        let script_name = script
+            .file_name()
+            .context("script did not have a file name")?
+            .to_str()
+            .context("filename was not valid UTF-8")?;
+        let ignored = [cache_directory.to_owned()];
 
-        let mut child = Command::new(target.join("bin").join(script_name))
-            .args(args)
+        let mut loaded = Ok((builder, directives));
+        let mut targets = None;
+
+        loop {
+            // Start watching before we build, so we don't miss changes made
+            // in the meantime. If the directives are broken or their files
+            // can't be watched, we keep watching what we watched before.
+            let (watched, watch) =
+                self.start_watching(script, &loaded, targets.take(), &ignored)?;
+            targets = Some(watched);
+
+            let mut child = None;
+            match loaded {
+                Ok((builder, directives)) => {
+                    match self.build_and_spawn(
+                        script,
+                        script_name,
+                        args,
+                        builder,
+                        &directives,
+                        cache_directory,
+                    ) {
+                        Ok(spawned) => child = Some(spawned),
+                        Err(err) => eprintln!("{err:?}"),
+                    }
+                }
+                Err(err) => eprintln!("{err:?}"),
+            }
+
+            self.wait_for_change(&watch, &mut child)?;
+
+            if let Some(child) = child {
+                watch::stop(child).context("could not stop the previous run")?;
+            }
+            eprintln!("nix-script: files changed, rebuilding");
+
+            loaded = self.load(script, &mut Timings::new(false)).and_then(
+                |(builder, mut directives)| {
+                    self.merge_options(&mut directives)?;
+                    Ok((builder, directives))
+                },
+            );
+        }
+    }
+
+    /// Watch the files of the newly loaded script. If we can't, say why and
+    /// keep watching what we watched before, or at least the script and its
+    /// includes, so fixing the problem still triggers a rebuild.
+    fn start_watching(
+        &self,
+        script: &Path,
+        loaded: &Result<(Builder, Directives)>,
+        previous: Option<Targets>,
+        ignored: &[PathBuf],
+    ) -> Result<(Targets, Watch)> {
+        let mut candidates = Vec::new();
+        let mut includes = Vec::new();
+
+        if let Ok((builder, directives)) = loaded {
+            match Targets::new(script, builder, directives) {
+                Ok(targets) => candidates.push(targets),
+                Err(err) => eprintln!("{:?}", err.context("could not find the files to watch")),
+            }
+            includes = directives.includes.clone();
+        }
+        candidates.extend(previous);
+        match Targets::script(script, &includes) {
+            Ok(targets) => candidates.push(targets),
+            Err(err) => eprintln!("{:?}", err.context("could not find the script to watch")),
+        }
+
+        for targets in candidates {
+            match Watch::new(targets.clone(), ignored) {
+                Ok(watch) => return Ok((targets, watch)),
+                Err(err) => eprintln!("{err:?}"),
+            }
+        }
+
+        anyhow::bail!("could not watch any of the script's files")
+    }
+
+    fn build_and_spawn(
+        &self,
+        script: &Path,
+        script_name: &str,
+        args: &[String],
+        builder: Builder,
+        directives: &Directives,
+        cache_directory: &Path,
+    ) -> Result<Child> {
+        let binary = self.binary(script_name, directives)?;
+        let target = self
+            .build_cached(
+                builder,
+                directives,
+                cache_directory,
+                script_name,
+                &mut Timings::new(false),
+            )
+            .context("could not build the script")?;
+
+        self.script_command(target, &binary, script, args, directives)?
             .spawn()
-            .context("could not start the script")?;
+            .context("could not run the script")
+    }
+
+    /// Wait until the script's files change, noting when the current run of
+    /// the script finishes in the meantime.
+    fn wait_for_change(&self, watch: &Watch, child: &mut Option<Child>) -> Result<()> {
+        loop {
+            if watch.changed(Duration::from_millis(250))? {
+                return Ok(());
+            }
+
+            if let Some(running) = child {
+                if let Some(status) = running
+                    .try_wait()
+                    .context("could not check on the script")?
+                {
+                    eprintln!("nix-script: script exited with {status}, waiting for changes");
+                    *child = None;
+                }
+            }
+        }
+    }
+
+    /// Parse the script's directives and set up a builder for it, without
+    /// applying any command-line overrides yet.
+    fn load(&self, script: &Path, timings: &mut Timings) -> Result<(Builder, Directives)> {
+        // Parse our directives, but don't combine them with command-line arguments yet!
+        let directives = Directives::from_file(self.indicator.as_deref(), script)
+            .context("could not parse directives from script")?;
+        timings.finish("parse");
+
+        let mut build_root = self.build_root.to_owned();
+        if build_root.is_none() {
+            if let Some(from_directives) = &directives.build_root {
+                let out = script
+                    .parent()
+                    .map(Path::to_path_buf)
+                    .unwrap_or_else(|| PathBuf::from("."));
+
+                out.join(from_directives)
+                    .canonicalize()
+                    .context("could not canonicalize final path to build root")?;
+
+                log::debug!("path to root from script directive: {}", out.display());
+
+                build_root = Some(out);
+            }
+        };
+        if build_root.is_none()
+            && (!self.runtime_files.is_empty()
+                || !directives.runtime_files.is_empty()
+                || !self.build_files.is_empty()
+                || !directives.build_files.is_empty())
+        {
+            log::warn!("Requested runtime or build files without specifying a build root. I am assuming it is the parent directory of the script for now, but you should set it explicitly!");
+            build_root = Some(
+                script
+                    .parent()
+                    .map(|p| p.to_owned())
+                    .unwrap_or_else(|| PathBuf::from(".")),
+            );
+        }
+
+        let mut builder = if let Some(build_root) = &build_root {
+            Builder::from_directory(build_root, script)
+                .context("could not initialize source in directory")?
+        } else {
+            Builder::from_script(script)
+        };
+        if self.strip_directives {
+            builder.strip_directives(&directives.indicators);
+        }
+        if self.whole_build_root {
+            builder.include_whole_root();
+        }
+        if self.keep_build_dir {
+            builder.keep_build_dir();
+        }
+        if self.print_derivation {
+            builder.print_derivation();
+        }
+        timings.finish("build-root");
+
+        Ok((builder, directives))
+    }
 
-        child.wait().context("could not run the script")
+    fn merge_options(&self, directives: &mut Directives) -> Result<()> {
+        directives.maybe_override_build_command(&self.build_command);
+        directives.maybe_override_check_command(&self.check_command);
+        directives.maybe_override_install_command(&self.install_command);
+        if self.skip_checks {
+            directives.skip_checks();
+        }
+        directives
+            .merge_build_inputs(&self.build_inputs)
+            .context("could not add build inputs provided on the command line")?;
+        if let Some(interpreter) = &self.interpreter {
+            directives.override_interpreter(interpreter)
+        }
+        directives
+            .merge_runtime_inputs(&self.runtime_inputs)
+            .context("could not add runtime inputs provided on the command line")?;
+        directives.merge_runtime_files(&self.runtime_files);
+        directives.merge_build_files(&self.build_files);
+        directives
+            .merge_binaries(&self.binaries)
+            .context("could not add binaries provided on the command line")?;
+        directives
+            .merge_env(&self.env)
+            .context("could not add environment variables provided on the command line")?;
+        directives
+            .merge_build_env(&self.build_env)
+            .context("could not add build environment variables provided on the command line")?;
+        directives
+            .merge_wrapper_args(&self.wrapper_args)
+            .context("could not add wrapper arguments provided on the command line")?;
+        // Paths on the command line are relative to where we were run, not
+        // to the script like the ones in directives.
+        if !self.allow_read.is_empty() || !self.allow_write.is_empty() {
+            let current_dir = env::current_dir().context("could not get the current directory")?;
+            let from_here = |paths: &[PathBuf]| -> Vec<PathBuf> {
+                paths.iter().map(|path| current_dir.join(path)).collect()
+            };
+            directives.merge_allow_read(&from_here(&self.allow_read));
+            directives.merge_allow_write(&from_here(&self.allow_write));
+        }
+        if self.allow_network {
+            directives.allow_network();
+        }
+        directives
+            .merge_allow_env(&self.allow_env)
+            .context("could not add allowed environment variables provided on the command line")?;
+        if let Some(expr) = &self.nixpkgs_config {
+            directives
+                .override_nixpkgs_config(expr)
+                .context("could not set nixpkgs config provided on the command line")?;
+        }
+
+        Ok(())
+    }
+
+    fn eject(&self, script: &Path, to: &Path, flake: bool, no_verify: bool) -> Result<ExitStatus> {
+        let script = clean_path(script).context("could not clean path to script")?;
+
+        let (mut builder, mut directives) = self.load(&script, &mut Timings::new(false))?;
+        self.merge_options(&mut directives)?;
+
+        let mut ejected =
+            eject::eject(&builder, &directives, to, flake).context("could not eject the script")?;
+
+        if no_verify {
+            log::info!("skipping verification of the ejected project");
+        } else {
+            let cache_directory = self
+                .get_cache_directory()
+                .context("could not get cache directory")?;
+
+            eject::verify(&mut builder, &mut ejected, &directives, &cache_directory)
+                .context("could not verify the ejected project")?;
+        }
+
+        Ok(ExitStatus::from_raw(0))
+    }
+
+    fn logs(&self, script: &Path) -> Result<ExitStatus> {
+        let script = clean_path(script).context("could not clean path to script")?;
+
+        let (builder, mut directives) = self.load(&script, &mut Timings::new(false))?;
+        self.merge_options(&mut directives)?;
+
+        let cache_directory = self
+            .get_cache_directory()
+            .context("could not get cache directory")?;
+        let hash = builder
+            .indexed_hash(&directives, &Index::new(&cache_directory))
+            .context("could not calculate the hash of the script")?;
+
+        let path = builder
+            .latest_log_path(&cache_directory, &hash)?
+            .with_context(|| {
+                format!(
+                    "there is no build log for any version of {}",
+                    script.display()
+                )
+            })?;
+        if path != builder.log_path(&cache_directory, &hash)? {
+            eprintln!(
+                "nix-script: the current version of {} has not been built; showing the newest log",
+                script.display()
+            );
+        }
+        let log = fs::read(&path)
+            .with_context(|| format!("could not read the build log at {}", path.display()))?;
+
+        io::stdout()
+            .write_all(&log)
+            .context("could not print the build log")?;
+
+        Ok(ExitStatus::from_raw(0))
+    }
+
+    /// Tell the script where it lives: `SCRIPT_FILE` as it was invoked (like
+    /// `$0` in a shell script), and the absolute `SCRIPT_PATH` and
+    /// `SCRIPT_DIR`. These are set at runtime, so they don't affect the cache.
+    fn set_script_location(&self, command: &mut Command, script: &Path) -> Result<()> {
+        let invoked = match self.script_and_args.first() {
+            Some(invoked) if self.eval.is_none() && invoked != "-" => invoked.to_owned(),
+            // Inline scripts weren't invoked by a path, so point at the file
+            // we wrote them to.
+            _ => script.display().to_string(),
+        };
+        let path = script
+            .canonicalize()
+            .context("could not find the absolute path to the script")?;
+        let dir = path
+            .parent()
+            .context("the script is not in a directory; this is a bug; please report")?;
+
+        log::trace!(
+            "setting SCRIPT_FILE to `{}`, SCRIPT_PATH to `{}` and SCRIPT_DIR to `{}`",
+            invoked,
+            path.display(),
+            dir.display()
+        );
+        command
+            .env("SCRIPT_FILE", invoked)
+            .env("SCRIPT_PATH", &path)
+            .env("SCRIPT_DIR", dir);
+
+        Ok(())
     }
 
     fn parse_script_and_args(&self) -> Result<(PathBuf, Vec<String>)> {
         log::trace!("parsing script and args");
+
+        if let Some(source) = &self.eval {
+            let script = self.write_inline(source)?;
+            return Ok((script, self.script_and_args.clone()));
+        }
+
         let mut script_and_args = self.script_and_args.iter();
 
-        let script = PathBuf::from(
-            script_and_args
-                .next()
-                .context("no script name; this is a bug; please report")?,
-        );
+        let first = script_and_args
+            .next()
+            .context("no script name; this is a bug; please report")?;
+        let script = if first == "-" {
+            let mut source = String::new();
+            io::stdin()
+                .read_to_string(&mut source)
+                .context("could not read the script from stdin")?;
+
+            self.write_inline(&source)?
+        } else {
+            PathBuf::from(first)
+        };
 
         Ok((script, self.script_and_args[1..].to_vec()))
     }
 
+    fn write_inline(&self, source: &str) -> Result<PathBuf> {
+        let cache_directory = self
+            .get_cache_directory()
+            .context("could not get cache directory")?;
+
+        inline::write(&cache_directory, &self.inline_name, source)
+            .context("could not write the inline script to the cache")
+    }
+
     fn get_cache_directory(&self) -> Result<PathBuf> {
         let mut target = match &self.cache_directory {
             Some(explicit) => explicit.to_owned(),
@@ -348,14 +999,38 @@ impl Opts {
 
         let mut command = Command::new("nix-shell");
 
-        log::trace!("setting SCRIPT_FILE to `{}`", script_file.display());
-        command.env("SCRIPT_FILE", script_file);
+        self.set_script_location(&mut command, &script_file)?;
 
         if self.pure {
             log::trace!("setting shell to pure mode");
             command.arg("--pure");
         }
 
+        for var in directives.build_env.iter().chain(&directives.env) {
+            let value = match var {
+                EnvVar::Set { value, .. } => value.to_owned(),
+                EnvVar::Prefix {
+                    name,
+                    separator,
+                    value,
+                } => match env::var(name) {
+                    Ok(old) if !old.is_empty() => format!("{value}{separator}{old}"),
+                    _ => value.to_owned(),
+                },
+                EnvVar::Suffix {
+                    name,
+                    separator,
+                    value,
+                } => match env::var(name) {
+                    Ok(old) if !old.is_empty() => format!("{old}{separator}{value}"),
+                    _ => value.to_owned(),
+                },
+            };
+
+            log::trace!("setting {} to `{}`", var.name(), value);
+            command.env(var.name(), value);
+        }
+
         for input in &directives.build_inputs {
             log::trace!("adding build input `{input}` to packages");
             command.arg("-p").arg(input.to_string());
@@ -371,10 +1046,8 @@ impl Opts {
             command.arg("--run").arg(run);
         }
 
-        command
-            .spawn()
-            .context("could not start nix-shell")?
-            .wait()
-            .context("could not start the shell")
+        // `nix-shell` cleans up after itself, so we have to stay around and
+        // can't `exec` it.
+        run_forwarding_signals(&mut command).context("could not run the shell")
     }
 }
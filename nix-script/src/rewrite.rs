@@ -0,0 +1,197 @@
+use anyhow::{Context, Result};
+use regex::{Captures, Regex};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+/// Rewrites file paths in build output so they point at the user's files
+/// instead of wherever the build happened to see them, keeping anything after
+/// the path (like `:12:5:`) as it was. That way editors can jump to errors.
+#[derive(Debug)]
+pub struct PathRewriter {
+    // Absolute directories the build may have seen the root as.
+    roots: Regex,
+    // Files from the root, mentioned relative to where the build ran.
+    files: Option<Regex>,
+    original_root: PathBuf,
+}
+
+impl PathRewriter {
+    /// `original_root` is where the files live for the user, `build_roots`
+    /// are other places we copied them to before building, and `files` are
+    /// the files (relative to the root) that the build knows about.
+    pub fn new(original_root: &Path, build_roots: &[PathBuf], files: &[PathBuf]) -> Result<Self> {
+        // Nix unpacks the source into the build directory (`/build` in the
+        // sandbox, somewhere in `/tmp` outside of it) under the name of the
+        // store path, which is `source` unless the root is used as is.
+        let mut source_names = vec![regex::escape("source")];
+        if let Some(name) = original_root.file_name().and_then(|name| name.to_str()) {
+            source_names.push(regex::escape(name));
+        }
+        let source_names = source_names.join("|");
+
+        let mut alternatives = vec![
+            format!(r"/nix/store/[0-9a-z]{{32}}-(?:{source_names})"),
+            format!(r"(?:/build|/tmp/nix-build-[^/\s]+)/(?:{source_names})"),
+        ];
+        alternatives.extend(
+            build_roots
+                .iter()
+                .map(|root| regex::escape(&root.display().to_string())),
+        );
+
+        let roots = Regex::new(&format!("(?:{})/", alternatives.join("|")))
+            .context("could not build a pattern for build directories")?;
+
+        let files = if files.is_empty() {
+            None
+        } else {
+            let mut names: Vec<String> = files
+                .iter()
+                .map(|file| file.display().to_string())
+                .collect();
+            // Longest first, so `hello.hs` wins over `hello` with an extension.
+            names.sort_by_key(|name| std::cmp::Reverse(name.len()));
+            let names: Vec<String> = names.iter().map(|name| regex::escape(name)).collect();
+
+            // Only paths that stand on their own and are followed by a line
+            // number, so we don't touch other mentions of the name. Build
+            // commands sometimes rename a file before compiling it (like the
+            // Haskell wrapper's `mv $SRC $SRC.hs`), so an extra extension is
+            // dropped.
+            Some(
+                Regex::new(&format!(
+                    r#"(^|[\s'"`(\[])(?:\./)?({})(?:\.[A-Za-z0-9]+)?(:\d)"#,
+                    names.join("|")
+                ))
+                .context("could not build a pattern for source files")?,
+            )
+        };
+
+        Ok(Self {
+            roots,
+            files,
+            original_root: original_root.to_owned(),
+        })
+    }
+
+    pub fn rewrite<'a>(&self, line: &'a str) -> Cow<'a, str> {
+        let root = format!("{}/", self.original_root.display());
+
+        let line = self.roots.replace_all(line, |_: &Captures| root.clone());
+
+        match &self.files {
+            Some(files) if files.is_match(&line) => Cow::Owned(
+                files
+                    .replace_all(&line, |captures: &Captures| {
+                        format!("{}{}{}{}", &captures[1], root, &captures[2], &captures[3])
+                    })
+                    .into_owned(),
+            ),
+            _ => line,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewriter() -> PathRewriter {
+        PathRewriter::new(
+            Path::new("/home/me/project"),
+            &[PathBuf::from("/home/me/.cache/nix-script/abc-hello.hs-src")],
+            &[PathBuf::from("hello.hs"), PathBuf::from("lib/Util.hs")],
+        )
+        .unwrap()
+    }
+
+    mod rewrite {
+        use super::*;
+
+        #[test]
+        fn store_paths() {
+            assert_eq!(
+                "/home/me/project/hello.hs:3:5: error:",
+                rewriter().rewrite(
+                    "/nix/store/0123456789abcdfghijklmnpqrsvwxyz-source/hello.hs:3:5: error:"
+                )
+            )
+        }
+
+        #[test]
+        fn sandbox_paths() {
+            assert_eq!(
+                "/home/me/project/lib/Util.hs:10:1: error:",
+                rewriter().rewrite("/build/source/lib/Util.hs:10:1: error:")
+            )
+        }
+
+        #[test]
+        fn unpacked_root_name() {
+            assert_eq!(
+                "In file included from /home/me/project/hello.hs:2:",
+                rewriter().rewrite("In file included from /build/project/hello.hs:2:")
+            )
+        }
+
+        #[test]
+        fn temporary_directories() {
+            assert_eq!(
+                "/home/me/project/hello.hs:1:1: warning",
+                rewriter()
+                    .rewrite("/home/me/.cache/nix-script/abc-hello.hs-src/hello.hs:1:1: warning")
+            )
+        }
+
+        #[test]
+        fn relative_files_with_line_numbers() {
+            assert_eq!(
+                "/home/me/project/hello.hs:3:5: error: (see /home/me/project/lib/Util.hs:2)",
+                rewriter().rewrite("hello.hs:3:5: error: (see ./lib/Util.hs:2)")
+            )
+        }
+
+        #[test]
+        fn leaves_other_mentions_alone() {
+            assert_eq!(
+                "compiling hello.hs to hello",
+                rewriter().rewrite("compiling hello.hs to hello")
+            )
+        }
+
+        #[test]
+        fn renamed_files() {
+            let rewriter = PathRewriter::new(
+                Path::new("/home/me/project"),
+                &[],
+                &[PathBuf::from("hello"), PathBuf::from("x.hs")],
+            )
+            .unwrap();
+
+            // What GHC says after `mv $SRC $SRC.hs; ghc … $SRC.hs`.
+            assert_eq!(
+                "/home/me/project/hello:3:5: error: [GHC-88464]",
+                rewriter.rewrite("hello.hs:3:5: error: [GHC-88464]")
+            );
+            assert_eq!(
+                "/home/me/project/x.hs:3:5: error: [GHC-88464]",
+                rewriter.rewrite("x.hs.hs:3:5: error: [GHC-88464]")
+            );
+        }
+
+        #[test]
+        fn prefers_files_that_exist() {
+            let rewriter = PathRewriter::new(
+                Path::new("/home/me/project"),
+                &[],
+                &[PathBuf::from("hello"), PathBuf::from("hello.hs")],
+            )
+            .unwrap();
+
+            assert_eq!(
+                "/home/me/project/hello.hs:3:5: error:",
+                rewriter.rewrite("hello.hs:3:5: error:")
+            );
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use anyhow::{Context, Result};
+use nix_script_directives::Directives;
+use path_absolutize::Absolutize;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Files programs need to look up hosts and check certificates. On NixOS,
+/// the ones in `/etc` are links into `/etc/static`.
+const NETWORK_FILES: &[&str] = &[
+    "/etc/resolv.conf",
+    "/etc/hosts",
+    "/etc/nsswitch.conf",
+    "/etc/ssl",
+    "/etc/static",
+];
+
+/// Runs a built script with bubblewrap, so it can only see the Nix store
+/// paths it depends on plus whatever its `allow*` directives let in.
+#[derive(Debug)]
+pub struct Sandbox {
+    closure: Vec<PathBuf>,
+    read: Vec<PathBuf>,
+    write: Vec<PathBuf>,
+    network: bool,
+    env: Vec<String>,
+}
+
+impl Sandbox {
+    /// Set up a sandbox for the build output at `out_path`. Relative paths in
+    /// the directives are relative to `base` (usually the script's directory),
+    /// so they mean the same thing wherever the script is run from. Paths the
+    /// script may write to are created if they don't exist yet.
+    pub fn new(out_path: &Path, base: &Path, directives: &Directives) -> Result<Self> {
+        let closure = closure(out_path).context("could not find the runtime dependencies")?;
+
+        let mut read = Vec::with_capacity(directives.allow_read.len());
+        for path in &directives.allow_read {
+            let path = absolute(path, base)?;
+            if !path.exists() {
+                anyhow::bail!(
+                    "cannot allow reading {} because it does not exist",
+                    path.display()
+                )
+            }
+            read.push(path);
+        }
+
+        let mut write = Vec::with_capacity(directives.allow_write.len());
+        for path in &directives.allow_write {
+            let path = absolute(path, base)?;
+            if !path.exists() {
+                log::debug!("creating {} so the script can write to it", path.display());
+                fs::create_dir_all(&path)
+                    .with_context(|| format!("could not create {}", path.display()))?;
+            }
+            write.push(path);
+        }
+
+        Ok(Self {
+            closure,
+            read,
+            write,
+            network: directives.allow_network,
+            env: directives.allow_env.clone(),
+        })
+    }
+
+    /// A command that runs `command` in the sandbox. Variables set on
+    /// `command` (like `SCRIPT_FILE`) are passed on, but of our own
+    /// environment only the allowed variables are.
+    pub fn wrap(&self, command: &Command) -> Command {
+        let mut out = Command::new("bwrap");
+        out.args(["--die-with-parent", "--unshare-all"]);
+
+        if self.network {
+            out.arg("--share-net");
+            for file in NETWORK_FILES {
+                out.args(["--ro-bind-try", file, file]);
+            }
+        }
+
+        out.args(["--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp"]);
+
+        for path in self.closure.iter().chain(&self.read) {
+            out.arg("--ro-bind").arg(path).arg(path);
+        }
+        for path in &self.write {
+            out.arg("--bind").arg(path).arg(path);
+        }
+
+        out.arg("--clearenv");
+        for name in &self.env {
+            match env::var_os(name) {
+                Some(value) => {
+                    out.args([OsStr::new("--setenv"), OsStr::new(name), &value]);
+                }
+                None => log::debug!("not passing {name} to the sandbox because it is not set"),
+            }
+        }
+        for (name, value) in command.get_envs() {
+            if let Some(value) = value {
+                out.args([OsStr::new("--setenv"), name, value]);
+            }
+        }
+
+        out.arg("--")
+            .arg(command.get_program())
+            .args(command.get_args());
+
+        out
+    }
+}
+
+/// The store paths `out_path` needs at runtime, including itself.
+fn closure(out_path: &Path) -> Result<Vec<PathBuf>> {
+    let output = Command::new("nix-store")
+        .arg("--query")
+        .arg("--requisites")
+        .arg(out_path)
+        .output()
+        .context("could not call nix-store. Is it on the PATH?")?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "nix-store failed to list the dependencies of {}: {}",
+            out_path.display(),
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        )
+    }
+
+    let stdout = String::from_utf8(output.stdout).context("could not read nix-store output")?;
+
+    Ok(stdout.lines().map(PathBuf::from).collect())
+}
+
+fn absolute(path: &Path, base: &Path) -> Result<PathBuf> {
+    Ok(path
+        .absolutize_from(base)
+        .with_context(|| format!("could not find the absolute path of {}", path.display()))?
+        .into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sandbox() -> Sandbox {
+        Sandbox {
+            closure: vec![PathBuf::from("/nix/store/abc-script")],
+            read: vec![PathBuf::from("/data")],
+            write: vec![PathBuf::from("/out")],
+            network: false,
+            env: vec!["NIX_SCRIPT_TEST_SANDBOX_UNSET".into()],
+        }
+    }
+
+    fn args(command: &Command) -> Vec<String> {
+        command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    mod absolute {
+        use super::*;
+
+        #[test]
+        fn resolves_relative_paths_against_the_base() {
+            assert_eq!(
+                PathBuf::from("/home/me/scripts/out"),
+                absolute(Path::new("./out"), Path::new("/home/me/scripts")).unwrap()
+            );
+            assert_eq!(
+                PathBuf::from("/home/me/data"),
+                absolute(Path::new("../data"), Path::new("/home/me/scripts")).unwrap()
+            );
+        }
+
+        #[test]
+        fn keeps_absolute_paths() {
+            assert_eq!(
+                PathBuf::from("/data"),
+                absolute(Path::new("/data"), Path::new("/home/me/scripts")).unwrap()
+            );
+        }
+    }
+
+    mod wrap {
+        use super::*;
+
+        #[test]
+        fn binds_closure_and_allowed_paths() {
+            let mut command = Command::new("/nix/store/abc-script/bin/script");
+            command.arg("--flag");
+
+            let wrapped = sandbox().wrap(&command);
+
+            assert_eq!("bwrap", wrapped.get_program());
+            let args = args(&wrapped).join(" ");
+            assert!(args.contains("--ro-bind /nix/store/abc-script /nix/store/abc-script"));
+            assert!(args.contains("--ro-bind /data /data"));
+            assert!(args.contains("--bind /out /out"));
+            assert!(args.ends_with("-- /nix/store/abc-script/bin/script --flag"));
+            assert!(!args.contains("--share-net"));
+        }
+
+        #[test]
+        fn shares_network_when_allowed() {
+            let mut sandbox = sandbox();
+            sandbox.network = true;
+
+            let args = args(&sandbox.wrap(&Command::new("script")));
+
+            assert!(args.contains(&"--share-net".to_string()));
+            assert!(args.contains(&"/etc/resolv.conf".to_string()));
+        }
+
+        #[test]
+        fn passes_only_allowed_and_command_env() {
+            let mut command = Command::new("script");
+            command.env("SCRIPT_FILE", "script.sh");
+
+            let args = args(&sandbox().wrap(&command)).join(" ");
+
+            assert!(args.contains("--clearenv --setenv SCRIPT_FILE script.sh --"));
+        }
+    }
+}
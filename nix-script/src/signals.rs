@@ -0,0 +1,86 @@
+use anyhow::{Context, Result};
+use signal_hook::consts::signal::{SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGUSR1, SIGUSR2};
+use signal_hook::iterator::Signals;
+use std::process::{Command, ExitStatus};
+
+/// Signals someone might send to our PID that the child should get instead.
+const FORWARDED: &[i32] = &[SIGHUP, SIGTERM, SIGUSR1, SIGUSR2];
+
+/// Signals the terminal already sends to the whole foreground process group,
+/// so the child gets them without our help. We only have to survive them.
+const IGNORED: &[i32] = &[SIGINT, SIGQUIT];
+
+/// Start `command` and wait for it to finish, passing on signals sent to us
+/// in the meantime. We start listening before the child exists, so a signal
+/// that arrives while it starts up is passed on as soon as it's running
+/// instead of killing us and leaving it behind.
+pub fn run_forwarding_signals(command: &mut Command) -> Result<ExitStatus> {
+    let mut signals = Signals::new(FORWARDED.iter().chain(IGNORED))
+        .context("could not set up signal forwarding")?;
+    let handle = signals.handle();
+
+    let mut child = command.spawn().with_context(|| {
+        format!(
+            "could not start {}",
+            command.get_program().to_string_lossy()
+        )
+    })?;
+
+    let pid = child.id();
+    let forwarder = std::thread::spawn(move || {
+        for signal in signals.forever() {
+            if FORWARDED.contains(&signal) {
+                log::debug!("forwarding signal {signal} to {pid}");
+                send(pid, signal);
+            }
+        }
+    });
+
+    let status = child.wait();
+
+    handle.close();
+    if forwarder.join().is_err() {
+        log::warn!("the signal forwarding thread panicked");
+    }
+
+    status.context("could not wait for the child process")
+}
+
+/// Send `signal` to the process `pid`. If it's already gone, nothing happens.
+pub fn send(pid: u32, signal: i32) {
+    // SAFETY: `kill` has no memory safety requirements. If the process is
+    // already gone, this fails harmlessly.
+    unsafe { libc::kill(pid as libc::pid_t, signal) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    mod run_forwarding_signals {
+        use super::*;
+
+        #[test]
+        fn reports_exit_code() {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg("exit 3");
+
+            assert_eq!(
+                Some(3),
+                run_forwarding_signals(&mut command).unwrap().code()
+            )
+        }
+
+        #[test]
+        fn reports_signal() {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg("kill -TERM $$");
+
+            assert_eq!(
+                Some(SIGTERM),
+                run_forwarding_signals(&mut command).unwrap().signal()
+            )
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use anyhow::{Context, Result};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How long each stage of a run took. Does nothing unless enabled, so it can
+/// be threaded through unconditionally.
+#[derive(Debug)]
+pub struct Timings {
+    enabled: bool,
+    start: Instant,
+    last: Instant,
+    stages: Vec<(&'static str, Duration)>,
+}
+
+impl Timings {
+    pub fn new(enabled: bool) -> Self {
+        let now = Instant::now();
+
+        Self {
+            enabled,
+            start: now,
+            last: now,
+            stages: Vec::new(),
+        }
+    }
+
+    /// Record that a stage just finished. It took the time since the last
+    /// stage finished (or since we started.)
+    pub fn finish(&mut self, stage: &'static str) {
+        if !self.enabled {
+            return;
+        }
+
+        let now = Instant::now();
+        self.stages.push((stage, now - self.last));
+        self.last = now;
+    }
+
+    /// Print the stages to stderr, or append them as a line of JSON to
+    /// `file` if given.
+    pub fn report(&self, script: &Path, file: Option<&Path>) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        let total = self.last - self.start;
+
+        match file {
+            Some(file) => {
+                let mut out = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(file)
+                    .with_context(|| format!("could not open {}", file.display()))?;
+
+                writeln!(out, "{}", self.to_json(script, total))
+                    .context("could not write timings")?;
+            }
+            None => eprint!("{}", self.to_text(total)),
+        }
+
+        Ok(())
+    }
+
+    fn to_text(&self, total: Duration) -> String {
+        let mut out = String::from("nix-script timings:\n");
+
+        for (stage, took) in self.stages.iter().chain(&[("total", total)]) {
+            out.push_str(&format!("  {:<12} {:>10.3}ms\n", stage, millis(*took)));
+        }
+
+        out
+    }
+
+    fn to_json(&self, script: &Path, total: Duration) -> serde_json::Value {
+        let stages: serde_json::Map<String, serde_json::Value> = self
+            .stages
+            .iter()
+            .map(|(stage, took)| (stage.to_string(), millis(*took).into()))
+            .collect();
+
+        serde_json::json!({
+            "script": script,
+            "stages_ms": stages,
+            "total_ms": millis(total),
+        })
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod finish {
+        use super::*;
+
+        #[test]
+        fn records_stages_in_order() {
+            let mut timings = Timings::new(true);
+            timings.finish("parse");
+            timings.finish("hash");
+
+            let stages: Vec<&str> = timings.stages.iter().map(|(stage, _)| *stage).collect();
+            assert_eq!(vec!["parse", "hash"], stages);
+        }
+
+        #[test]
+        fn does_nothing_when_disabled() {
+            let mut timings = Timings::new(false);
+            timings.finish("parse");
+
+            assert!(timings.stages.is_empty());
+        }
+    }
+
+    mod to_json {
+        use super::*;
+
+        #[test]
+        fn includes_stages_and_total() {
+            let mut timings = Timings::new(true);
+            timings.finish("parse");
+
+            let json = timings.to_json(Path::new("script.sh"), Duration::from_millis(2));
+            assert_eq!("script.sh", json["script"]);
+            assert!(json["stages_ms"]["parse"].is_f64());
+            assert_eq!(2.0, json["total_ms"]);
+        }
+    }
+}
@@ -0,0 +1,388 @@
+use crate::builder::Builder;
+use crate::signals;
+use anyhow::{Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use nix_script_directives::Directives;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// How long to wait for more changes after the first one, so saving several
+/// files at once only causes one rebuild.
+const SETTLE: Duration = Duration::from_millis(100);
+
+/// How long a stopped run gets to clean up before we kill it.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The files one version of a script is built from.
+#[derive(Debug, Clone)]
+pub struct Targets {
+    // The build root, if the script has one. Watched recursively.
+    root: Option<PathBuf>,
+    // Absolute paths of the files (or directories) the build uses.
+    files: Vec<PathBuf>,
+    whole_root: bool,
+}
+
+impl Targets {
+    pub fn new(script: &Path, builder: &Builder, directives: &Directives) -> Result<Self> {
+        let files_root = builder
+            .files_root()
+            .context("could not find the build root")?
+            .canonicalize()
+            .context("could not find the absolute path to the build root")?;
+
+        let mut files: Vec<PathBuf> = builder
+            .source_files(directives)
+            .context("could not find the files the script is built from")?
+            .iter()
+            .map(|file| files_root.join(file))
+            .collect();
+        files.extend(directives.includes.iter().cloned());
+
+        // Without a build root, the only file in the script's directory that
+        // matters is the script itself.
+        let root = if builder.has_build_root() {
+            Some(files_root)
+        } else {
+            let name = script
+                .file_name()
+                .context("script did not have a file name")?;
+            files.push(files_root.join(name));
+            None
+        };
+
+        files.sort();
+        files.dedup();
+
+        Ok(Self {
+            root,
+            files,
+            whole_root: builder.includes_whole_root(),
+        })
+    }
+
+    /// Just the script and the files it includes, for when we can't tell what
+    /// else it's built from.
+    pub fn script(script: &Path, includes: &[PathBuf]) -> Result<Self> {
+        let mut files = vec![script
+            .canonicalize()
+            .context("could not find the absolute path to the script")?];
+        files.extend(includes.iter().cloned());
+
+        files.sort();
+        files.dedup();
+
+        Ok(Self {
+            root: None,
+            files,
+            whole_root: false,
+        })
+    }
+
+    /// The directories to watch: the build root, plus the directories of any
+    /// files outside of it. We watch directories instead of files because
+    /// editors often save by replacing the file.
+    fn directories(&self) -> Vec<(PathBuf, RecursiveMode)> {
+        let mut out = Vec::new();
+
+        if let Some(root) = &self.root {
+            out.push((root.clone(), RecursiveMode::Recursive));
+        }
+
+        for file in &self.files {
+            if self
+                .root
+                .as_ref()
+                .is_some_and(|root| file.starts_with(root))
+            {
+                continue;
+            }
+
+            let dir = if file.is_dir() {
+                file.as_path()
+            } else {
+                match file.parent() {
+                    Some(parent) => parent,
+                    None => continue,
+                }
+            };
+
+            if !out.iter().any(|(watched, _)| watched == dir) {
+                out.push((dir.to_owned(), RecursiveMode::NonRecursive));
+            }
+        }
+
+        out
+    }
+}
+
+/// Watches the files of a script and says when any of them changed.
+#[derive(Debug)]
+pub struct Watch {
+    // Kept so the watch stays active.
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+    filter: Filter,
+}
+
+impl Watch {
+    /// Start watching. Changes inside `ignored` (like the cache directory)
+    /// never count.
+    pub fn new(targets: Targets, ignored: &[PathBuf]) -> Result<Self> {
+        let (sender, events) = channel();
+        let mut watcher =
+            notify::recommended_watcher(sender).context("could not set up a file watcher")?;
+
+        for (dir, mode) in targets.directories() {
+            log::debug!("watching {} ({:?})", dir.display(), mode);
+            watcher
+                .watch(&dir, mode)
+                .with_context(|| format!("could not watch {}", dir.display()))?;
+        }
+
+        Ok(Self {
+            _watcher: watcher,
+            events,
+            filter: Filter::new(targets, ignored)?,
+        })
+    }
+
+    /// Wait up to `timeout` for one of the files to change. After a change,
+    /// wait until things settle down before returning.
+    pub fn changed(&self, timeout: Duration) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        let mut changed = false;
+
+        loop {
+            let wait = if changed {
+                SETTLE
+            } else {
+                deadline.saturating_duration_since(Instant::now())
+            };
+
+            match self.events.recv_timeout(wait) {
+                Ok(Ok(event)) => {
+                    if matches!(event.kind, EventKind::Access(_)) {
+                        continue;
+                    }
+
+                    if let Some(path) = event.paths.iter().find(|p| self.filter.matches(p)) {
+                        log::debug!("{} changed ({:?})", path.display(), event.kind);
+                        changed = true;
+                    }
+                }
+                Ok(Err(err)) => log::warn!("error while watching files: {err}"),
+                Err(RecvTimeoutError::Timeout) => return Ok(changed),
+                Err(RecvTimeoutError::Disconnected) => {
+                    anyhow::bail!("the file watcher stopped unexpectedly")
+                }
+            }
+        }
+    }
+}
+
+/// Decides which changed paths matter.
+#[derive(Debug)]
+struct Filter {
+    targets: Targets,
+    ignored: Vec<PathBuf>,
+    gitignore: Gitignore,
+}
+
+impl Filter {
+    fn new(targets: Targets, ignored: &[PathBuf]) -> Result<Self> {
+        let gitignore = match &targets.root {
+            Some(root) => {
+                let mut builder = GitignoreBuilder::new(root);
+                for name in [".gitignore", ".ignore"] {
+                    let file = root.join(name);
+                    if file.exists() {
+                        if let Some(err) = builder.add(&file) {
+                            log::warn!("ignoring problem in {}: {err}", file.display());
+                        }
+                    }
+                }
+                builder
+                    .build()
+                    .context("could not read ignore rules from the build root")?
+            }
+            None => Gitignore::empty(),
+        };
+
+        Ok(Self {
+            targets,
+            ignored: ignored.to_vec(),
+            gitignore,
+        })
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        if self.ignored.iter().any(|ignored| path.starts_with(ignored)) {
+            return false;
+        }
+
+        // Files the build names directly always count.
+        if self.targets.files.iter().any(|file| file == path) {
+            return true;
+        }
+
+        let root = match &self.targets.root {
+            Some(root) if path.starts_with(root) => root,
+            _ => return false,
+        };
+        if path.starts_with(root.join(".git")) {
+            return false;
+        }
+
+        let in_build =
+            self.targets.whole_root || self.targets.files.iter().any(|file| path.starts_with(file));
+
+        in_build
+            && !self
+                .gitignore
+                .matched_path_or_any_parents(path, path.is_dir())
+                .is_ignore()
+    }
+}
+
+/// Stop a run of the script: ask nicely first, then kill it if it doesn't
+/// finish in time.
+pub fn stop(mut child: Child) -> Result<()> {
+    if child
+        .try_wait()
+        .context("could not check on the script")?
+        .is_some()
+    {
+        return Ok(());
+    }
+
+    signals::send(child.id(), libc::SIGTERM);
+
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    while Instant::now() < deadline {
+        if child
+            .try_wait()
+            .context("could not check on the script")?
+            .is_some()
+        {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    log::warn!("the script did not stop within {STOP_TIMEOUT:?}; killing it");
+    child.kill().context("could not kill the script")?;
+    child.wait().context("could not wait for the script")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn filter(root: &Path, files: &[&str], whole_root: bool) -> Filter {
+        Filter::new(
+            Targets {
+                root: Some(root.to_owned()),
+                files: files.iter().map(|file| root.join(file)).collect(),
+                whole_root,
+            },
+            &[root.join("cache")],
+        )
+        .unwrap()
+    }
+
+    mod matches {
+        use super::*;
+
+        #[test]
+        fn files_the_build_uses() {
+            let root = tempdir().unwrap();
+            let filter = filter(root.path(), &["script.sh", "data"], false);
+
+            assert!(filter.matches(&root.path().join("script.sh")));
+            assert!(filter.matches(&root.path().join("data/input.json")));
+            assert!(!filter.matches(&root.path().join("README.md")));
+        }
+
+        #[test]
+        fn anything_in_whole_root() {
+            let root = tempdir().unwrap();
+            let filter = filter(root.path(), &["script.sh"], true);
+
+            assert!(filter.matches(&root.path().join("README.md")));
+        }
+
+        #[test]
+        fn not_ignored_paths() {
+            let root = tempdir().unwrap();
+            fs::write(root.path().join(".gitignore"), "*.log\n").unwrap();
+            let filter = filter(root.path(), &["script.sh"], true);
+
+            assert!(!filter.matches(&root.path().join("build.log")));
+            assert!(!filter.matches(&root.path().join(".git/index")));
+            assert!(!filter.matches(&root.path().join("cache/abc-script.sh")));
+        }
+
+        #[test]
+        fn not_outside_the_root() {
+            let root = tempdir().unwrap();
+            let filter = filter(root.path(), &["script.sh"], true);
+
+            assert!(!filter.matches(Path::new("/somewhere/else")));
+        }
+    }
+
+    mod script {
+        use super::*;
+
+        #[test]
+        fn watches_the_script_and_includes() {
+            let root = tempdir().unwrap();
+            let root_path = root.path().canonicalize().unwrap();
+            let script = root_path.join("script.sh");
+            fs::write(&script, "echo hi").unwrap();
+            let include = root_path.join("common.sh");
+            let includes = vec![include.clone()];
+
+            let targets = Targets::script(&script, &includes).unwrap();
+
+            assert_eq!(None, targets.root);
+            assert_eq!(vec![include, script], targets.files);
+        }
+    }
+
+    mod changed {
+        use super::*;
+
+        #[test]
+        fn notices_changes_to_the_script() {
+            let root = tempdir().unwrap();
+            let root_path = root.path().canonicalize().unwrap();
+            let script = root_path.join("script.sh");
+            fs::write(&script, "echo hi").unwrap();
+
+            let watch = Watch::new(
+                Targets {
+                    root: None,
+                    files: vec![script.clone()],
+                    whole_root: false,
+                },
+                &[],
+            )
+            .unwrap();
+
+            fs::write(root_path.join("unrelated"), "").unwrap();
+            assert!(!watch.changed(Duration::from_millis(200)).unwrap());
+
+            fs::write(&script, "echo bye").unwrap();
+            assert!(watch.changed(Duration::from_secs(5)).unwrap());
+        }
+    }
+}
@@ -32,6 +32,7 @@ mod sample_scripts {
 mod io_behavior {
     use super::*;
     use std::os::unix::fs::symlink;
+    use std::os::unix::process::ExitStatusExt;
     use std::path::PathBuf;
     use tempfile::tempdir;
 
@@ -56,6 +57,13 @@ mod io_behavior {
         assert.code(32);
     }
 
+    #[test]
+    fn runs_as_the_script() {
+        let output = bin().arg("tests/kill-self.sh").output().unwrap();
+
+        assert_eq!(Some(15), output.status.signal());
+    }
+
     #[test]
     fn forwards_stdin() {
         let assert = bin()
@@ -81,8 +89,12 @@ mod io_behavior {
         // Mess with the symlink to make it point to an invalid destination. Note
         // that we can't use the more ergonomic `DirEntry.path()` here because
         // it traverses symlinks.
-        let mut cache_entries = std::fs::read_dir(temp.path()).unwrap();
-        let filename = cache_entries.next().unwrap().unwrap().file_name();
+        let filename = std::fs::read_dir(temp.path())
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .find(|entry| entry.file_type().unwrap().is_symlink())
+            .unwrap()
+            .file_name();
         let link = temp.path().join(filename);
         std::fs::remove_file(&link).unwrap();
         symlink(PathBuf::from("garbage"), &link).unwrap();
@@ -121,6 +133,141 @@ mod io_behavior {
             .stdout("Hello, World!\n");
     }
 
+    #[test]
+    fn metadata_block() {
+        bin()
+            .arg("tests/metadata-block.sh")
+            //
+            .assert()
+            .success()
+            .stdout("Hello, Block!\n");
+    }
+
+    #[test]
+    fn failing_check() {
+        bin()
+            .arg("tests/failing-check.sh")
+            //
+            .assert()
+            .failure();
+    }
+
+    #[test]
+    fn skip_checks() {
+        bin()
+            .arg("--skip-checks")
+            .arg("tests/failing-check.sh")
+            //
+            .assert()
+            .success()
+            .stdout("Hello, Check!\n");
+    }
+
+    #[test]
+    fn exec_binary() {
+        bin()
+            .arg("--exec")
+            .arg("helper")
+            .arg("tests/with-binaries.sh")
+            //
+            .assert()
+            .success()
+            .stdout("Hello from helper!\n");
+    }
+
+    #[test]
+    fn exec_unknown_binary() {
+        bin()
+            .arg("--exec")
+            .arg("nope")
+            .arg("tests/with-binaries.sh")
+            //
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains(
+                "`nope` is not one of this script's binaries",
+            ));
+    }
+
+    #[test]
+    fn export_single_script() {
+        bin()
+            .arg("--export")
+            .arg("tests/echo.sh")
+            //
+            .assert()
+            .success()
+            .stdout(predicates::str::contains("pkgs.writeTextFile"));
+    }
+
+    #[test]
+    fn export_call_package() {
+        bin()
+            .arg("--export")
+            .arg("--export-format")
+            .arg("callpackage")
+            .arg("tests/echo.sh")
+            //
+            .assert()
+            .success()
+            .stdout(predicates::str::contains("stdenv.mkDerivation"))
+            .stdout(predicates::str::contains("meta = {"));
+    }
+
+    #[test]
+    fn eject() {
+        let to = tempdir().unwrap();
+
+        bin()
+            .arg("eject")
+            .arg("tests/with_runtime_file/script.sh")
+            .arg("--to")
+            .arg(to.path())
+            .arg("--flake")
+            //
+            .assert()
+            .success();
+
+        for file in ["default.nix", "shell.nix", "flake.nix"] {
+            assert!(to.path().join(file).exists(), "{file} was not written");
+        }
+    }
+
+    #[test]
+    fn logs_without_build() {
+        let cache = tempdir().unwrap();
+
+        bin()
+            .env("NIX_SCRIPT_CACHE", cache.path())
+            .arg("logs")
+            .arg("tests/echo.sh")
+            //
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains("there is no build log"));
+    }
+
+    #[test]
+    fn logs_after_build() {
+        let cache = tempdir().unwrap();
+
+        bin()
+            .env("NIX_SCRIPT_CACHE", cache.path())
+            .arg("--quiet-build")
+            .arg("tests/echo.sh")
+            //
+            .assert()
+            .success();
+
+        bin()
+            .env("NIX_SCRIPT_CACHE", cache.path())
+            .arg("logs")
+            .arg("tests/echo.sh")
+            //
+            .assert()
+            .success();
+    }
+
     #[test]
     fn script_file() {
         bin()
@@ -128,7 +275,59 @@ mod io_behavior {
             //
             .assert()
             .success()
-            .stdout("script-name.sh\n");
+            .stdout("tests/script-name.sh\n");
+    }
+
+    #[test]
+    fn script_path_and_dir() {
+        let dir = PathBuf::from("tests").canonicalize().unwrap();
+
+        bin()
+            .arg("tests/script-dir.sh")
+            //
+            .assert()
+            .success()
+            .stdout(format!(
+                "{}\n{}\n",
+                dir.join("script-dir.sh").display(),
+                dir.display()
+            ));
+    }
+
+    #[test]
+    fn inline_source() {
+        let cache = tempdir().unwrap();
+
+        bin()
+            .arg("--cache-directory")
+            .arg(cache.path())
+            .arg("--build-command")
+            .arg("cp $SRC $OUT")
+            .arg("-e")
+            .arg("#!/usr/bin/env bash\necho \"Hello, $1!\"")
+            .arg("Inline")
+            //
+            .assert()
+            .success()
+            .stdout("Hello, Inline!\n");
+    }
+
+    #[test]
+    fn source_from_stdin() {
+        let cache = tempdir().unwrap();
+
+        bin()
+            .arg("--cache-directory")
+            .arg(cache.path())
+            .arg("--build-command")
+            .arg("cp $SRC $OUT")
+            .arg("-")
+            .arg("Stdin")
+            .write_stdin("#!/usr/bin/env bash\necho \"Hello, $1!\"")
+            //
+            .assert()
+            .success()
+            .stdout("Hello, Stdin!\n");
     }
 
     #[test]
@@ -0,0 +1,8 @@
+#!/usr/bin/env nix-script
+#!build cp $SRC $OUT
+#!check grep -q "never printed" $OUT
+#!interpreter bash
+#!runtimeInputs bash
+set -euo pipefail
+
+echo "Hello, Check!"
@@ -0,0 +1,8 @@
+#!/usr/bin/env nix-script
+#!build cp $SRC $OUT
+#!interpreter bash
+#!runtimeInputs bash
+set -euo pipefail
+
+# Since nix-script replaces itself with the script, this is nix-script's PID.
+kill -TERM $$
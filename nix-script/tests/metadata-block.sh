@@ -0,0 +1,9 @@
+#!/usr/bin/env nix-script
+# /// nix-script
+# build = "cp $SRC $OUT"
+# interpreter = "bash"
+# runtimeInputs = ["bash", "jq"]
+# ///
+set -euo pipefail
+
+echo '{"message": "Hello, Block!"}' | jq -r .message
@@ -0,0 +1,8 @@
+#!/usr/bin/env nix-script
+#!build cp $SRC $OUT
+#!interpreter bash
+#!runtimeInputs bash
+set -euo pipefail
+
+echo "$SCRIPT_PATH"
+echo "$SCRIPT_DIR"
@@ -0,0 +1,8 @@
+#!/usr/bin/env nix-script
+#!build cp $SRC $OUT && printf '#!/bin/sh\necho Hello from helper!\n' > bin/helper && chmod +x bin/helper
+#!binaries helper
+#!interpreter bash
+#!runtimeInputs bash
+set -euo pipefail
+
+echo "Hello from main!"